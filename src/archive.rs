@@ -0,0 +1,621 @@
+// Copyright 2024 Martin Pool
+
+//! Copy trees into and out of archives.
+
+#[cfg(feature = "tar")]
+use std::io::BufRead;
+#[cfg(feature = "zip")]
+use std::io::Seek;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::*;
+
+#[cfg(feature = "tar")]
+impl<'f> CopyOptions<'f> {
+    /// Copy the tree at `src` into a tar archive written to `writer`, rather than
+    /// into a directory.
+    ///
+    /// Entries are filtered, counted, and passed to callbacks just as they would be
+    /// for [CopyOptions::copy_tree]. Paths in the archive are relative to `src`, which
+    /// itself is not included as an entry, or are chosen by [CopyOptions::map_path].
+    ///
+    /// The archive records the type, permissions and mtime of each entry, according to
+    /// [CopyOptions::file_mode] and [CopyOptions::dir_mode], and the atime if
+    /// [CopyOptions::copy_atime] is set. The owner and group are recorded if
+    /// [CopyOptions::preserve_owner] is set, and are otherwise 0. Extended attributes
+    /// and ACLs are not stored, and [CopyOptions::sync] has no effect: the caller
+    /// can sync the writer if needed.
+    ///
+    /// The archive is finished with end-of-archive markers when the copy succeeds.
+    /// Errors writing the archive are reported with the path within the archive.
+    ///
+    /// This is only available with the `tar` feature.
+    ///
+    /// ```
+    /// use cp_r::CopyOptions;
+    ///
+    /// let mut archive = Vec::new();
+    /// let stats = CopyOptions::new()
+    ///     .copy_tree_to_tar("src", &mut archive)
+    ///     .unwrap();
+    /// assert!(stats.files > 0);
+    /// ```
+    pub fn copy_tree_to_tar<P, W>(self, src: P, writer: W) -> Result<CopyStats>
+    where
+        P: AsRef<Path>,
+        W: Write + 'f,
+    {
+        let writer: Box<dyn Write + 'f> = Box::new(writer);
+        let builder = tar::Builder::new(writer);
+        let mut iter = CopyIter::new_tar(self, src.as_ref().to_owned(), builder);
+        for event in &mut iter {
+            if let CopyEvent::Error(err) = event {
+                return Err(err);
+            }
+        }
+        Ok(iter.into_stats())
+    }
+
+    /// Append one entry, at `entry_subpath` relative to the top of the tree, to a tar
+    /// archive as `path`, returning the number of bytes of content.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn append_to_tar(
+        &mut self,
+        builder: &mut tar::Builder<Box<dyn Write + 'f>>,
+        src: &Path,
+        entry_subpath: &Path,
+        path: &Path,
+        kind: Kind,
+        entry: &Entry,
+        stats: &mut CopyStats,
+    ) -> Result<u64> {
+        self.check_total_limits(entry, stats)?;
+        let src_metadata = entry_metadata(entry)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(unix_seconds(src_metadata.mtime));
+        if self.copy_atime {
+            if let Some(gnu) = header.as_gnu_mut() {
+                gnu.set_atime(unix_seconds(src_metadata.atime));
+            }
+        }
+        let (mut uid, mut gid) = (0, 0);
+        if self.preserve_owner {
+            uid = src_metadata.uid;
+            gid = src_metadata.gid;
+            if let Some(map_uid) = &mut self.map_uid {
+                uid = map_uid(uid);
+            }
+            if let Some(map_gid) = &mut self.map_gid {
+                gid = map_gid(gid);
+            }
+        }
+        header.set_uid(uid.into());
+        header.set_gid(gid.into());
+        // Platforms without Unix permissions report a mode of 0, which would make
+        // the extracted entries inaccessible.
+        let src_mode = match (src_metadata.mode, kind) {
+            (0, Kind::Dir) => 0o755,
+            (0, _) => 0o644,
+            (mode, _) => mode,
+        };
+        let write_err = |io| Error::from_io_error(io, ErrorKind::WriteFile, path);
+        match kind {
+            Kind::File => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(self.file_mode.apply(src_mode));
+                let mut reader = self
+                    .file_system
+                    .open(src)
+                    .map_err(|io| Error::from_io_error(io, ErrorKind::ReadFile, src))?;
+                let copy_start = Instant::now();
+                let len = if self.should_transform(entry_subpath, entry) {
+                    // The length must be known before the header is written, so the
+                    // transformed content is held in memory.
+                    let mut content = Vec::new();
+                    let len = self.transform_file(entry_subpath, &mut reader, &mut content, src)?;
+                    header.set_size(len);
+                    builder
+                        .append_data(&mut header, path, content.as_slice())
+                        .map_err(write_err)?;
+                    len
+                } else {
+                    let len = src_metadata.len;
+                    header.set_size(len);
+                    // The header records the length seen when the directory was listed,
+                    // so if the file has changed since then, truncate or pad it to that
+                    // length to keep the archive well-formed.
+                    let content = reader.chain(std::io::repeat(0)).take(len);
+                    builder
+                        .append_data(&mut header, path, content)
+                        .map_err(|io| Error::from_io_error(io, ErrorKind::CopyFile, src))?;
+                    len
+                };
+                stats.copy_file_time += copy_start.elapsed();
+                stats.add_file(entry_subpath, len);
+                Ok(len)
+            }
+            Kind::Dir => {
+                header.set_entry_type(tar::EntryType::Directory);
+                let mode = match self.dir_mode {
+                    Some(dir_mode) => dir_mode.apply(src_mode),
+                    None => src_mode,
+                };
+                header.set_mode(mode);
+                header.set_size(0);
+                builder
+                    .append_data(&mut header, path, std::io::empty())
+                    .map_err(write_err)?;
+                stats.dirs += 1;
+                Ok(0)
+            }
+            Kind::Symlink => {
+                let target = self
+                    .file_system
+                    .read_link(src)
+                    .map_err(|io| Error::from_io_error(io, ErrorKind::ReadSymlink, src))?;
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_size(0);
+                builder
+                    .append_link(&mut header, path, target)
+                    .map_err(write_err)?;
+                stats.symlinks += 1;
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Return the number of whole seconds since the Unix epoch, or 0 for earlier times.
+#[cfg(feature = "tar")]
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl<'f> CopyOptions<'f> {
+    /// Copy the entries of a tar archive into the directory `dest`.
+    ///
+    /// The archive is read from `reader`, and may be gzip-compressed, which is detected
+    /// automatically.
+    ///
+    /// Entries are materialized in `dest` with the same rules as
+    /// [CopyOptions::copy_tree], applying the [CopyOptions::filter],
+    /// [CopyOptions::map_path], [CopyOptions::transform_files] and
+    /// [CopyOptions::after_entry_copied] callbacks to each entry by its path within the
+    /// archive. Parent directories that aren't in the
+    /// archive are created as needed.
+    ///
+    /// Entries with absolute paths or `..` components, or that would be written through a
+    /// symlink, including one at the entry's own path, are rejected with an
+    /// [ErrorKind::UnsafePath] error, so that the archive can't write outside `dest`.
+    /// Hard links and special files cause an [ErrorKind::UnsupportedFileType] error.
+    ///
+    /// Extended attributes and ACLs are not copied from archives.
+    ///
+    /// This is only available with the `tar` feature.
+    ///
+    /// ```
+    /// use cp_r::CopyOptions;
+    ///
+    /// let mut archive = Vec::new();
+    /// CopyOptions::new().copy_tree_to_tar("src", &mut archive).unwrap();
+    ///
+    /// let dest = tempfile::tempdir().unwrap();
+    /// let stats = CopyOptions::new()
+    ///     .copy_from_tar(archive.as_slice(), &dest)
+    ///     .unwrap();
+    /// assert!(dest.path().join("lib.rs").is_file());
+    /// ```
+    #[cfg(feature = "tar")]
+    pub fn copy_from_tar<R, Q>(mut self, reader: R, dest: Q) -> Result<CopyStats>
+    where
+        R: Read,
+        Q: AsRef<Path>,
+    {
+        let read_err = |io| Error::from_io_error(io, ErrorKind::ReadArchive, PathBuf::new());
+        let mut reader = std::io::BufReader::new(reader);
+        let reader: Box<dyn Read> = if reader
+            .fill_buf()
+            .map_err(read_err)?
+            .starts_with(&[0x1f, 0x8b])
+        {
+            Box::new(flate2::bufread::GzDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+        let mut archive = tar::Archive::new(reader);
+        let mut extractor = Extractor::new(&mut self, dest.as_ref())?;
+        for entry in archive.entries().map_err(read_err)? {
+            let mut entry = entry.map_err(read_err)?;
+            let path = entry.path().map_err(read_err)?.into_owned();
+            let read_err = |io| Error::from_io_error(io, ErrorKind::ReadArchive, &path);
+            let header = entry.header();
+            let file_type = match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => FileType::File,
+                tar::EntryType::Directory => FileType::Dir,
+                tar::EntryType::Symlink => FileType::Symlink,
+                // Global metadata isn't an entry in the tree.
+                tar::EntryType::XGlobalHeader => continue,
+                _ => FileType::Other,
+            };
+            let mut metadata = Metadata::new(file_type);
+            metadata.len = entry.size();
+            metadata.mode = header.mode().map_err(read_err)? & 0o7777;
+            // Some archivers leave the owner fields empty.
+            metadata.uid = header.uid().unwrap_or(0) as u32;
+            metadata.gid = header.gid().unwrap_or(0) as u32;
+            metadata.mtime = UNIX_EPOCH + Duration::from_secs(header.mtime().map_err(read_err)?);
+            metadata.atime = match header.as_gnu().map(|gnu| gnu.atime()) {
+                Some(Ok(atime)) if atime != 0 => UNIX_EPOCH + Duration::from_secs(atime),
+                _ => metadata.mtime,
+            };
+            let link_target = match file_type {
+                FileType::Symlink => entry
+                    .link_name()
+                    .map_err(read_err)?
+                    .map(|target| target.into_owned()),
+                _ => None,
+            };
+            extractor.extract(&path, metadata, &mut entry, link_target)?;
+        }
+        extractor.finish()
+    }
+
+    /// Copy the entries of a zip archive into the directory `dest`.
+    ///
+    /// This behaves like [CopyOptions::copy_from_tar], including rejecting unsafe paths.
+    /// Zip archives record modification times without a time zone, and they are
+    /// interpreted as UTC.
+    ///
+    /// This is only available with the `zip` feature.
+    #[cfg(feature = "zip")]
+    pub fn copy_from_zip<R, Q>(mut self, reader: R, dest: Q) -> Result<CopyStats>
+    where
+        R: Read + Seek,
+        Q: AsRef<Path>,
+    {
+        let mut archive = zip::ZipArchive::new(reader).map_err(|err| {
+            Error::from_io_error(err.into(), ErrorKind::ReadArchive, PathBuf::new())
+        })?;
+        let mut extractor = Extractor::new(&mut self, dest.as_ref())?;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).map_err(|err| {
+                Error::from_io_error(err.into(), ErrorKind::ReadArchive, PathBuf::new())
+            })?;
+            let path = PathBuf::from(file.name());
+            let file_type = if file.is_dir() {
+                FileType::Dir
+            } else if file.is_symlink() {
+                FileType::Symlink
+            } else {
+                FileType::File
+            };
+            let mut metadata = Metadata::new(file_type);
+            metadata.len = file.size();
+            metadata.mode = file.unix_mode().unwrap_or(0) & 0o7777;
+            if let Some(mtime) = file.last_modified() {
+                metadata.mtime = zip_time(mtime);
+                metadata.atime = metadata.mtime;
+            }
+            let link_target = if file_type == FileType::Symlink {
+                // The content of a symlink entry is its target.
+                let mut target = Vec::new();
+                file.read_to_end(&mut target)
+                    .map_err(|io| Error::from_io_error(io, ErrorKind::ReadArchive, &path))?;
+                Some(path_from_bytes(target))
+            } else {
+                None
+            };
+            extractor.extract(&path, metadata, &mut file, link_target)?;
+        }
+        extractor.finish()
+    }
+}
+
+/// Materializes entries read from an archive into a destination directory.
+struct Extractor<'o, 'f> {
+    options: &'o mut CopyOptions<'f>,
+    dest: PathBuf,
+    stats: CopyStats,
+    /// Directories that were filtered out or skipped by limits, whose contents are skipped.
+    filtered_dirs: Vec<PathBuf>,
+    /// The destination paths extracted so far, and what kind of entry each one is.
+    dest_paths: HashMap<PathBuf, Kind>,
+    /// Permissions to set on directories after everything is extracted.
+    dir_modes: Vec<(PathBuf, u32)>,
+    /// Directories whose entries should be synced at the end.
+    sync_dirs: Vec<PathBuf>,
+    start_time: Instant,
+}
+
+impl<'o, 'f> Extractor<'o, 'f> {
+    /// Create the destination if necessary.
+    fn new(options: &'o mut CopyOptions<'f>, dest: &Path) -> Result<Extractor<'o, 'f>> {
+        options.harden(&[dest], ErrorKind::CreateDir)?;
+        let mut extractor = Extractor {
+            options,
+            dest: dest.to_owned(),
+            stats: CopyStats::default(),
+            filtered_dirs: Vec::new(),
+            dest_paths: HashMap::new(),
+            dir_modes: Vec::new(),
+            sync_dirs: Vec::new(),
+            start_time: Instant::now(),
+        };
+        let fs = &*extractor.options.file_system;
+        if !fs.metadata(dest).is_ok_and(|m| m.file_type.is_dir()) {
+            if !extractor.options.create_destination {
+                return Err(Error::new(ErrorKind::DestinationDoesNotExist, dest));
+            }
+            copy_dir(fs, dest, &mut extractor.stats)?;
+            if let Some(parent) = dest.parent() {
+                extractor.sync_dirs.push(parent.to_owned());
+            }
+        }
+        extractor.sync_dirs.push(dest.to_owned());
+        Ok(extractor)
+    }
+
+    /// Extract one entry.
+    fn extract(
+        &mut self,
+        path: &Path,
+        metadata: Metadata,
+        content: &mut dyn Read,
+        link_target: Option<PathBuf>,
+    ) -> Result<()> {
+        if let Some(cancel) = &self.options.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(Error::new(ErrorKind::Interrupted, path));
+            }
+        }
+        let path = safe_relative_path(path)?;
+        if path.as_os_str().is_empty() {
+            // An entry for the top directory, such as `./`.
+            return Ok(());
+        }
+        if self.filtered_dirs.iter().any(|dir| path.starts_with(dir)) {
+            return Ok(());
+        }
+        let file_type = metadata.file_type;
+        let entry = Entry::new(path.clone(), metadata.clone());
+        if let Some(filter) = &mut self.options.filter {
+            if !filter(&path, &entry)? {
+                self.stats.filtered_out += 1;
+                if file_type.is_dir() {
+                    self.filtered_dirs.push(path);
+                }
+                return Ok(());
+            }
+        }
+        if self
+            .options
+            .check_limits(&path, &entry, &mut self.stats)?
+            .is_some()
+        {
+            // As when copying a tree, the contents of a skipped directory aren't counted.
+            if file_type.is_dir() {
+                self.filtered_dirs.push(path);
+            }
+            return Ok(());
+        }
+        self.options.check_total_limits(&entry, &self.stats)?;
+        let kind = match file_type {
+            FileType::File => Kind::File,
+            FileType::Dir => Kind::Dir,
+            FileType::Symlink => Kind::Symlink,
+            FileType::Other => return Err(Error::new(ErrorKind::UnsupportedFileType, path)),
+        };
+        // A directory merged into an existing one is handled like any directory that
+        // already exists.
+        let dest_subpath =
+            match self
+                .options
+                .map_dest_path(&path, &entry, kind, &mut self.dest_paths)?
+            {
+                Some((dest_subpath, _merged)) => dest_subpath,
+                None => {
+                    self.stats.filtered_out += 1;
+                    if file_type.is_dir() {
+                        self.filtered_dirs.push(path);
+                    }
+                    return Ok(());
+                }
+            };
+        if self.options.map_path.is_none() {
+            // Archives can hold several entries for one path, such as a file updated by
+            // `tar --append`, but nothing may be written through a symlink extracted earlier.
+            if self.dest_paths.insert(dest_subpath.clone(), kind) == Some(Kind::Symlink) {
+                return Err(Error::new(ErrorKind::UnsafePath, path));
+            }
+        }
+        self.create_parents(&dest_subpath)?;
+        let dest_path = self.dest.join(&dest_subpath);
+        let transform = self.options.should_transform(&path, &entry);
+        match kind {
+            Kind::File => {
+                let write_err = |io| Error::from_io_error(io, ErrorKind::WriteFile, &dest_path);
+                let mut writer = self
+                    .options
+                    .file_system
+                    .create(&dest_path, 0o600)
+                    .map_err(write_err)?;
+                let copy_start = Instant::now();
+                let bytes = if transform {
+                    self.options
+                        .transform_file(&path, content, &mut writer, &path)?
+                } else {
+                    std::io::copy(content, &mut writer)
+                        .map_err(|io| Error::from_io_error(io, ErrorKind::CopyFile, &dest_path))?
+                };
+                self.stats.copy_file_time += copy_start.elapsed();
+                writer.flush().map_err(write_err)?;
+                if matches!(self.options.sync, SyncMode::Files | SyncMode::FilesAndDirs) {
+                    sync_writer(&mut *writer, &dest_path)?;
+                }
+                drop(writer);
+                set_file_mode(
+                    &*self.options.file_system,
+                    &dest_path,
+                    file_mode_or_default(metadata.mode),
+                    self.options.file_mode,
+                    &mut self.stats,
+                )?;
+                self.stats.add_file(&path, bytes);
+            }
+            Kind::Dir => {
+                let fs = &*self.options.file_system;
+                // The directory may already have been created as the parent of an
+                // earlier entry.
+                if !fs
+                    .symlink_metadata(&dest_path)
+                    .is_ok_and(|m| m.file_type.is_dir())
+                {
+                    copy_dir(fs, &dest_path, &mut self.stats)?;
+                    self.sync_dirs.push(dest_path.clone());
+                }
+                if let Some(dir_mode) = self.options.dir_mode {
+                    self.dir_modes
+                        .push((dest_path.clone(), dir_mode.apply(metadata.mode)));
+                }
+            }
+            Kind::Symlink => {
+                let target = link_target.unwrap_or_default();
+                self.options
+                    .file_system
+                    .symlink(&target, &dest_path, false)
+                    .map_err(|io| Error::from_io_error(io, ErrorKind::CreateSymlink, &dest_path))?;
+                self.stats.symlinks += 1;
+            }
+        }
+        if let Some(owner) = self.options.dest_owner(&metadata) {
+            self.options
+                .copy_owner(owner, &metadata, &dest_path, kind)?;
+        }
+        self.options
+            .copy_times(&metadata, &dest_path, kind, &mut self.stats)?;
+        self.stats.elapsed = self.start_time.elapsed();
+        if let Some(ref mut f) = self.options.after_entry_copied {
+            f(&path, &file_type, &self.stats)?;
+        }
+        Ok(())
+    }
+
+    /// Create any missing parent directories of an entry, and check that neither they nor
+    /// the entry itself are symlinks, which could redirect the entry outside of the
+    /// destination.
+    fn create_parents(&mut self, path: &Path) -> Result<()> {
+        let fs = &*self.options.file_system;
+        let dest_path = self.dest.join(path);
+        match fs.symlink_metadata(&dest_path) {
+            Ok(metadata) if metadata.file_type.is_symlink() => {
+                return Err(Error::new(ErrorKind::UnsafePath, path));
+            }
+            _ => (),
+        }
+        let mut parent = self.dest.clone();
+        let mut components = path.components();
+        components.next_back();
+        for component in components {
+            parent.push(component);
+            match fs.symlink_metadata(&parent) {
+                Ok(metadata) if metadata.file_type.is_symlink() => {
+                    return Err(Error::new(ErrorKind::UnsafePath, path));
+                }
+                Ok(_) => (),
+                Err(io) if io.kind() == std::io::ErrorKind::NotFound => {
+                    copy_dir(fs, &parent, &mut self.stats)?;
+                    self.sync_dirs.push(parent.clone());
+                }
+                Err(io) => return Err(Error::from_io_error(io, ErrorKind::CreateDir, &parent)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Set directory permissions and sync, and return the stats.
+    fn finish(mut self) -> Result<CopyStats> {
+        let fs = &*self.options.file_system;
+        // Archive entries can be in any order, so set permissions on the deepest
+        // directories first.
+        self.dir_modes
+            .sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, mode) in &self.dir_modes {
+            set_mode(fs, path, *mode)?;
+        }
+        if self.options.sync == SyncMode::FilesAndDirs {
+            for dir in &self.sync_dirs {
+                sync_dir(fs, dir)?;
+            }
+        }
+        if self.options.sync == SyncMode::EndOnly {
+            sync_filesystem(fs, &self.dest)?;
+        }
+        self.stats.elapsed = self.start_time.elapsed();
+        Ok(self.stats)
+    }
+}
+
+/// Check that an archive path is relative and doesn't contain `..`, and remove any
+/// `.` components.
+fn safe_relative_path(path: &Path) -> Result<PathBuf> {
+    let mut safe = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => safe.push(name),
+            Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(Error::new(ErrorKind::UnsafePath, path));
+            }
+        }
+    }
+    Ok(safe)
+}
+
+/// Archives from platforms without Unix permissions may have a mode of 0, which would
+/// make the extracted files inaccessible.
+fn file_mode_or_default(mode: u32) -> u32 {
+    if mode == 0 {
+        0o644
+    } else {
+        mode
+    }
+}
+
+#[cfg(feature = "zip")]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        std::ffi::OsString::from_vec(bytes).into()
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+}
+
+/// Convert a zip timestamp, taken to be in UTC, to a [SystemTime].
+#[cfg(feature = "zip")]
+fn zip_time(time: zip::DateTime) -> SystemTime {
+    // Days since the epoch of a date in the proleptic Gregorian calendar, from
+    // Howard Hinnant's `days_from_civil`.
+    let (month, day) = (i64::from(time.month()), i64::from(time.day()));
+    let year = i64::from(time.year()) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let seconds = days * 86400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    // Zip dates start in 1980, so this is never before the epoch.
+    UNIX_EPOCH + Duration::from_secs(seconds as u64)
+}
@@ -0,0 +1,472 @@
+// Copyright 2024 Martin Pool
+
+//! A filesystem wrapper that injects errors, for testing error handling.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::filesystem::{FileSystem, Metadata, ReadDir, WriteFile};
+
+/// A [FileSystem] that passes operations through to another filesystem, except for
+/// those that match a configured [Fault], which fail with a chosen error.
+///
+/// This can be used to deterministically test how code that copies trees handles
+/// errors, such as the disk filling up while writing a particular file.
+///
+/// Clones share the same faults and counters, so a clone can be passed to
+/// [CopyOptions::file_system](crate::CopyOptions::file_system) and then
+/// [FaultyFileSystem::faults_injected] checked on the original.
+///
+/// ```
+/// use std::io;
+/// use std::path::Path;
+/// use cp_r::*;
+///
+/// let fs = MemoryFileSystem::new();
+/// fs.create_dir(Path::new("/src")).unwrap();
+/// for name in ["a", "b", "c"] {
+///     fs.write_file(Path::new("/src").join(name), b"content").unwrap();
+/// }
+///
+/// // Fail the second call to `write` under `/dest`, as if the disk were full. Each of
+/// // these small files is written in one call, so this fails copying the second file.
+/// let faulty = FaultyFileSystem::new(fs).fail(
+///     Fault::new(Operation::Write)
+///         .path("/dest/*")
+///         .nth(2)
+///         .error_kind(io::ErrorKind::WriteZero),
+/// );
+/// let err = CopyOptions::new()
+///     .file_system(faulty.clone())
+///     .copy_tree("/src", "/dest")
+///     .unwrap_err();
+/// assert_eq!(err.kind(), ErrorKind::CopyFile);
+/// assert_eq!(err.io_error().unwrap().kind(), io::ErrorKind::WriteZero);
+/// assert_eq!(faulty.faults_injected(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct FaultyFileSystem<F> {
+    inner: F,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    faults: Vec<Fault>,
+    injected: usize,
+}
+
+/// An operation on a [FileSystem] that can be made to fail by a [FaultyFileSystem].
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    /// [FileSystem::read_dir].
+    ReadDir,
+    /// [FileSystem::metadata] or [FileSystem::symlink_metadata].
+    Metadata,
+    /// [FileSystem::create_dir].
+    CreateDir,
+    /// [FileSystem::open].
+    Open,
+    /// A read from a file returned by [FileSystem::open].
+    Read,
+    /// [FileSystem::create].
+    Create,
+    /// A write to a file returned by [FileSystem::create].
+    ///
+    /// Each call to `write` is counted separately, and copying a larger file may take
+    /// several calls.
+    Write,
+    /// [FileSystem::read_link].
+    ReadLink,
+    /// [FileSystem::symlink], matched against the path of the new link.
+    Symlink,
+    /// [FileSystem::hard_link], matched against the path of the new link.
+    HardLink,
+    /// [FileSystem::copy_file], matched against the source path.
+    CopyFile,
+    /// [FileSystem::set_permissions].
+    SetPermissions,
+    /// [FileSystem::set_times] or [FileSystem::set_symlink_times].
+    SetTimes,
+    /// [FileSystem::set_owner].
+    SetOwner,
+    /// Listing, reading or writing extended attributes.
+    Xattr,
+    /// [WriteFile::sync], or any of the other sync operations.
+    Sync,
+}
+
+/// A rule describing which operations a [FaultyFileSystem] should fail, and how.
+///
+/// By default a fault fails every call to its [Operation], on any path, with an
+/// [io::ErrorKind::Other] error.
+#[derive(Debug)]
+pub struct Fault {
+    operation: Operation,
+    pattern: Option<String>,
+    nth: Option<usize>,
+    error: FaultError,
+    /// The number of calls that matched the operation and pattern so far.
+    calls: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FaultError {
+    Kind(io::ErrorKind),
+    Os(i32),
+}
+
+impl Fault {
+    /// Construct a fault that fails every call to an operation.
+    pub fn new(operation: Operation) -> Fault {
+        Fault {
+            operation,
+            pattern: None,
+            nth: None,
+            error: FaultError::Kind(io::ErrorKind::Other),
+            calls: 0,
+        }
+    }
+
+    /// Only fail operations on paths matching a glob pattern.
+    ///
+    /// The pattern is matched against the whole path passed to the filesystem.
+    /// `?` matches any one character, `*` matches any characters within one path
+    /// component, and `**` matches any characters including separators.
+    #[must_use]
+    pub fn path<S: Into<String>>(self, pattern: S) -> Fault {
+        Fault {
+            pattern: Some(pattern.into()),
+            ..self
+        }
+    }
+
+    /// Only fail the `n`th matching call, counting from 1; other calls succeed.
+    ///
+    /// Calls are counted for each operation, not for each file: for [Operation::Write]
+    /// a single file may be written in several calls.
+    #[must_use]
+    pub fn nth(self, n: usize) -> Fault {
+        Fault {
+            nth: Some(n),
+            ..self
+        }
+    }
+
+    /// Fail with an error of this kind.
+    #[must_use]
+    pub fn error_kind(self, kind: io::ErrorKind) -> Fault {
+        Fault {
+            error: FaultError::Kind(kind),
+            ..self
+        }
+    }
+
+    /// Fail with this operating system error code, such as `libc::ENOSPC`.
+    #[must_use]
+    pub fn os_error(self, code: i32) -> Fault {
+        Fault {
+            error: FaultError::Os(code),
+            ..self
+        }
+    }
+
+    /// Count a call, and return an error if it should fail.
+    fn check(&mut self, operation: Operation, path: &Path) -> Option<io::Error> {
+        if operation != self.operation {
+            return None;
+        }
+        if let Some(pattern) = &self.pattern {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let path: Vec<char> = path.to_string_lossy().chars().collect();
+            if !glob_match(&pattern, &path) {
+                return None;
+            }
+        }
+        self.calls += 1;
+        if self.nth.is_some_and(|n| n != self.calls) {
+            return None;
+        }
+        Some(match self.error {
+            FaultError::Kind(kind) => io::Error::new(
+                kind,
+                format!("injected fault in {:?} on {:?}", operation, path),
+            ),
+            FaultError::Os(code) => io::Error::from_raw_os_error(code),
+        })
+    }
+}
+
+impl<F: FileSystem> FaultyFileSystem<F> {
+    /// Wrap a filesystem, initially without any faults.
+    pub fn new(inner: F) -> FaultyFileSystem<F> {
+        FaultyFileSystem {
+            inner,
+            state: Arc::default(),
+        }
+    }
+
+    /// Add a fault.
+    ///
+    /// If several faults match a call, the first one added that fails it is used.
+    #[must_use]
+    pub fn fail(self, fault: Fault) -> FaultyFileSystem<F> {
+        self.lock().faults.push(fault);
+        self
+    }
+
+    /// Return the number of errors injected so far.
+    pub fn faults_injected(&self) -> usize {
+        self.lock().injected
+    }
+
+    /// Return the wrapped filesystem.
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Return an error if any fault fails this call.
+    fn check(&self, operation: Operation, path: &Path) -> io::Result<()> {
+        check(&self.state, operation, path)
+    }
+
+    fn has_faults_for(&self, operations: &[Operation]) -> bool {
+        self.lock()
+            .faults
+            .iter()
+            .any(|fault| operations.contains(&fault.operation))
+    }
+}
+
+fn check(state: &Mutex<State>, operation: Operation, path: &Path) -> io::Result<()> {
+    let mut state = state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Every matching fault counts the call, even if an earlier one fails it.
+    let mut error = None;
+    for fault in &mut state.faults {
+        if let Some(err) = fault.check(operation, path) {
+            error.get_or_insert(err);
+        }
+    }
+    match error {
+        Some(err) => {
+            state.injected += 1;
+            Err(err)
+        }
+        None => Ok(()),
+    }
+}
+
+impl<F: FileSystem> FileSystem for FaultyFileSystem<F> {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        self.check(Operation::ReadDir, path)?;
+        self.inner.read_dir(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.check(Operation::Metadata, path)?;
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.check(Operation::Metadata, path)?;
+        self.inner.symlink_metadata(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.check(Operation::CreateDir, path)?;
+        self.inner.create_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        self.check(Operation::Open, path)?;
+        Ok(Box::new(FaultyReader {
+            inner: self.inner.open(path)?,
+            state: Arc::clone(&self.state),
+            path: path.to_owned(),
+        }))
+    }
+
+    fn create(&self, path: &Path, mode: u32) -> io::Result<Box<dyn WriteFile>> {
+        self.check(Operation::Create, path)?;
+        Ok(Box::new(FaultyWriter {
+            inner: self.inner.create(path, mode)?,
+            state: Arc::clone(&self.state),
+            path: path.to_owned(),
+        }))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.check(Operation::ReadLink, path)?;
+        self.inner.read_link(path)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path, target_is_dir: bool) -> io::Result<()> {
+        self.check(Operation::Symlink, link)?;
+        self.inner.symlink(target, link, target_is_dir)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        self.check(Operation::HardLink, link)?;
+        self.inner.hard_link(original, link)
+    }
+
+    fn copy_file(&self, src: &Path, dest: &Path, sync: bool) -> io::Result<u64> {
+        self.check(Operation::CopyFile, src)?;
+        if self.has_faults_for(&[
+            Operation::Open,
+            Operation::Read,
+            Operation::Create,
+            Operation::Write,
+        ]) || (sync && self.has_faults_for(&[Operation::Sync]))
+        {
+            // Copy through our own readers and writers so that those faults apply.
+            let mode = self.metadata(src)?.mode;
+            let mut reader = self.open(src)?;
+            let mut writer = self.create(dest, mode)?;
+            let bytes_copied = io::copy(&mut reader, &mut writer)?;
+            writer.flush()?;
+            if sync {
+                writer.sync()?;
+            }
+            Ok(bytes_copied)
+        } else {
+            self.inner.copy_file(src, dest, sync)
+        }
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.check(Operation::SetPermissions, path)?;
+        self.inner.set_permissions(path, mode)
+    }
+
+    fn set_times(
+        &self,
+        path: &Path,
+        atime: Option<SystemTime>,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        self.check(Operation::SetTimes, path)?;
+        self.inner.set_times(path, atime, mtime)
+    }
+
+    fn set_symlink_times(
+        &self,
+        path: &Path,
+        atime: SystemTime,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        self.check(Operation::SetTimes, path)?;
+        self.inner.set_symlink_times(path, atime, mtime)
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        self.check(Operation::SetOwner, path)?;
+        self.inner.set_owner(path, uid, gid)
+    }
+
+    fn list_xattrs(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        self.check(Operation::Xattr, path)?;
+        self.inner.list_xattrs(path)
+    }
+
+    fn get_xattr(&self, path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        self.check(Operation::Xattr, path)?;
+        self.inner.get_xattr(path, name)
+    }
+
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        self.check(Operation::Xattr, path)?;
+        self.inner.set_xattr(path, name, value)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.check(Operation::Sync, path)?;
+        self.inner.sync_dir(path)
+    }
+
+    fn sync_filesystem(&self, path: &Path) -> io::Result<()> {
+        self.check(Operation::Sync, path)?;
+        self.inner.sync_filesystem(path)
+    }
+}
+
+struct FaultyReader {
+    inner: Box<dyn Read + Send>,
+    state: Arc<Mutex<State>>,
+    path: PathBuf,
+}
+
+impl Read for FaultyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check(&self.state, Operation::Read, &self.path)?;
+        self.inner.read(buf)
+    }
+}
+
+struct FaultyWriter {
+    inner: Box<dyn WriteFile>,
+    state: Arc<Mutex<State>>,
+    path: PathBuf,
+}
+
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        check(&self.state, Operation::Write, &self.path)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl WriteFile for FaultyWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        check(&self.state, Operation::Sync, &self.path)?;
+        self.inner.sync()
+    }
+}
+
+/// Match a path against a glob pattern, as described in [Fault::path].
+fn glob_match(pattern: &[char], path: &[char]) -> bool {
+    fn is_separator(c: char) -> bool {
+        c == '/' || (cfg!(windows) && c == '\\')
+    }
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            for i in 0..=path.len() {
+                if glob_match(rest, &path[i..]) {
+                    return true;
+                }
+                if i < path.len() && is_separator(path[i]) {
+                    return false;
+                }
+            }
+            false
+        }
+        ['?', rest @ ..] => match path {
+            [c, path_rest @ ..] if !is_separator(*c) => glob_match(rest, path_rest),
+            _ => false,
+        },
+        [p, rest @ ..] => match path {
+            [c, path_rest @ ..] if c == p || (is_separator(*c) && is_separator(*p)) => {
+                glob_match(rest, path_rest)
+            }
+            _ => false,
+        },
+    }
+}
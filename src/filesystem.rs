@@ -0,0 +1,530 @@
+// Copyright 2024 Martin Pool
+
+//! Abstract filesystem operations, so that trees can be copied within other
+//! filesystems such as [MemoryFileSystem](crate::MemoryFileSystem).
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use filetime::FileTime;
+
+/// The entries in a directory, returned by [FileSystem::read_dir].
+pub type ReadDir = Box<dyn Iterator<Item = io::Result<Entry>> + Send>;
+
+/// A file open for writing, returned by [FileSystem::create].
+pub trait WriteFile: Write + Send {
+    /// Make sure the content of the file is durable.
+    ///
+    /// This syncs the open file, so it works even if the file's permissions don't
+    /// allow it to be reopened. The default implementation does nothing.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteFile for fs::File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// A filesystem that trees can be copied from and to.
+///
+/// The source and destination of a copy are in the same filesystem, set by
+/// [CopyOptions::file_system](crate::CopyOptions::file_system). By default
+/// this is [StdFileSystem], the real filesystem.
+///
+/// Paths are passed as they were given to [CopyOptions::copy_tree](crate::CopyOptions::copy_tree),
+/// joined with paths relative to the top of the tree.
+///
+/// Methods for optional features have default implementations that return
+/// [io::ErrorKind::Unsupported] errors, or do nothing where that is harmless.
+pub trait FileSystem: Send + Sync {
+    /// List the entries in a directory, not including `.` and `..`.
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir>;
+
+    /// Return the metadata for a path, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Return the metadata for a path, without following symlinks.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Create a new directory, whose parent must already exist.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Open a file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Create a file for writing, or truncate it if it already exists.
+    ///
+    /// `mode` is the Unix permission bits for a new file, where supported.
+    fn create(&self, path: &Path, mode: u32) -> io::Result<Box<dyn WriteFile>>;
+
+    /// Return the target of a symlink.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Create a symlink at `link` pointing to `target`.
+    ///
+    /// `target_is_dir` says whether the target is a directory, which matters on Windows.
+    fn symlink(&self, target: &Path, link: &Path, target_is_dir: bool) -> io::Result<()>;
+
+    /// Create a hard link at `link` to the existing file `original`.
+    fn hard_link(&self, _original: &Path, _link: &Path) -> io::Result<()> {
+        Err(unsupported("hard links"))
+    }
+
+    /// Copy the content of a file to a new file, with the same permissions, returning the
+    /// number of bytes copied.
+    ///
+    /// If `sync` is true the new file is synced, as by [WriteFile::sync], before it's
+    /// closed.
+    ///
+    /// The default implementation reads and writes through [FileSystem::open] and
+    /// [FileSystem::create].
+    fn copy_file(&self, src: &Path, dest: &Path, sync: bool) -> io::Result<u64> {
+        let mode = self.metadata(src)?.mode;
+        let mut reader = self.open(src)?;
+        let mut writer = self.create(dest, mode)?;
+        let bytes_copied = io::copy(&mut reader, &mut writer)?;
+        writer.flush()?;
+        if sync {
+            writer.sync()?;
+        }
+        Ok(bytes_copied)
+    }
+
+    /// Set the Unix permission bits of a file or directory.
+    fn set_permissions(&self, _path: &Path, _mode: u32) -> io::Result<()> {
+        Err(unsupported("setting permissions"))
+    }
+
+    /// Set the modification time, and optionally the access time, of a file.
+    ///
+    /// If `atime` is `None` the access time is not changed.
+    fn set_times(
+        &self,
+        _path: &Path,
+        _atime: Option<SystemTime>,
+        _mtime: SystemTime,
+    ) -> io::Result<()> {
+        Err(unsupported("setting timestamps"))
+    }
+
+    /// Set the times of a symlink, not its target.
+    fn set_symlink_times(
+        &self,
+        _path: &Path,
+        _atime: SystemTime,
+        _mtime: SystemTime,
+    ) -> io::Result<()> {
+        Err(unsupported("setting symlink timestamps"))
+    }
+
+    /// Set the owner and group of a path, without following symlinks.
+    fn set_owner(&self, _path: &Path, _uid: u32, _gid: u32) -> io::Result<()> {
+        Err(unsupported("setting owners"))
+    }
+
+    /// List the names of the extended attributes of a path, without following symlinks.
+    fn list_xattrs(&self, _path: &Path) -> io::Result<Vec<OsString>> {
+        Err(unsupported("extended attributes"))
+    }
+
+    /// Return the value of an extended attribute, or `None` if it doesn't exist.
+    fn get_xattr(&self, _path: &Path, _name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        Err(unsupported("extended attributes"))
+    }
+
+    /// Set the value of an extended attribute.
+    fn set_xattr(&self, _path: &Path, _name: &OsStr, _value: &[u8]) -> io::Result<()> {
+        Err(unsupported("extended attributes"))
+    }
+
+    /// Make sure the entries in a directory are durable.
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Make sure everything written to the filesystem containing `path` is durable.
+    fn sync_filesystem(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported by this filesystem", what),
+    )
+}
+
+/// The type of a file: a plain file, directory, symlink, or something else.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum FileType {
+    /// A plain file.
+    File,
+    /// A directory.
+    Dir,
+    /// A symbolic link.
+    Symlink,
+    /// Some other type of file that can't be copied, such as a Unix FIFO or device.
+    Other,
+}
+
+impl FileType {
+    /// True if this is a plain file.
+    pub fn is_file(self) -> bool {
+        self == FileType::File
+    }
+
+    /// True if this is a directory.
+    pub fn is_dir(self) -> bool {
+        self == FileType::Dir
+    }
+
+    /// True if this is a symlink.
+    pub fn is_symlink(self) -> bool {
+        self == FileType::Symlink
+    }
+}
+
+impl From<fs::FileType> for FileType {
+    fn from(file_type: fs::FileType) -> FileType {
+        if file_type.is_file() {
+            FileType::File
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else if file_type.is_symlink() {
+            FileType::Symlink
+        } else {
+            FileType::Other
+        }
+    }
+}
+
+/// Metadata about a file, directory, or symlink.
+///
+/// Fields that aren't supported by a platform or filesystem are 0.
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[non_exhaustive]
+pub struct Metadata {
+    /// The type of the file.
+    pub file_type: FileType,
+    /// The length of the file in bytes.
+    pub len: u64,
+    /// The Unix permission bits.
+    pub mode: u32,
+    /// The Unix user id of the owner.
+    pub uid: u32,
+    /// The Unix group id.
+    pub gid: u32,
+    /// The last access time.
+    pub atime: SystemTime,
+    /// The last modification time.
+    pub mtime: SystemTime,
+    /// The id of the device holding the file, which differs between mounted
+    /// filesystems.
+    pub dev: u64,
+    /// The inode number, which together with [Metadata::dev] identifies the file.
+    pub ino: u64,
+}
+
+impl Metadata {
+    /// Construct metadata for a file of the given type, with other fields zero.
+    pub fn new(file_type: FileType) -> Metadata {
+        Metadata {
+            file_type,
+            len: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            dev: 0,
+            ino: 0,
+        }
+    }
+}
+
+impl From<fs::Metadata> for Metadata {
+    fn from(metadata: fs::Metadata) -> Metadata {
+        #[cfg(unix)]
+        let (mode, uid, gid, dev, ino) = {
+            use std::os::unix::fs::MetadataExt;
+            (
+                metadata.mode() & 0o7777,
+                metadata.uid(),
+                metadata.gid(),
+                metadata.dev(),
+                metadata.ino(),
+            )
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid, dev, ino) = (0, 0, 0, 0, 0);
+        Metadata {
+            file_type: metadata.file_type().into(),
+            len: metadata.len(),
+            mode,
+            uid,
+            gid,
+            atime: system_time(FileTime::from_last_access_time(&metadata)),
+            mtime: system_time(FileTime::from_last_modification_time(&metadata)),
+            dev,
+            ino,
+        }
+    }
+}
+
+pub(crate) fn system_time(file_time: FileTime) -> SystemTime {
+    let nanos = Duration::from_nanos(file_time.nanoseconds().into());
+    if file_time.unix_seconds() >= 0 {
+        UNIX_EPOCH + Duration::from_secs(file_time.unix_seconds() as u64) + nanos
+    } else {
+        UNIX_EPOCH - Duration::from_secs(file_time.unix_seconds().unsigned_abs()) + nanos
+    }
+}
+
+/// An entry in a directory, returned by [FileSystem::read_dir] and passed to
+/// [CopyOptions::filter](crate::CopyOptions::filter).
+#[derive(Debug, Clone)]
+pub struct Entry {
+    path: PathBuf,
+    file_name: OsString,
+    file_type: FileType,
+    /// The metadata, once it's known.
+    metadata: OnceLock<Metadata>,
+    /// The directory entry to read the metadata from when it's first needed, if it
+    /// wasn't known when the directory was listed.
+    dir_entry: Option<Arc<fs::DirEntry>>,
+}
+
+impl Entry {
+    /// Construct an entry for the file at `path`.
+    ///
+    /// The metadata should describe the entry itself, without following symlinks.
+    pub fn new(path: PathBuf, metadata: Metadata) -> Entry {
+        let file_name = path.file_name().unwrap_or_default().to_owned();
+        Entry {
+            path,
+            file_name,
+            file_type: metadata.file_type,
+            metadata: OnceLock::from(metadata),
+            dir_entry: None,
+        }
+    }
+
+    /// The full path of the entry: the directory that was listed joined with the
+    /// file name.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The name of the entry within its directory.
+    pub fn file_name(&self) -> &OsStr {
+        &self.file_name
+    }
+
+    /// The type of the entry, not following symlinks.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// The metadata of the entry, not following symlinks.
+    ///
+    /// Listing a real directory only finds the type of each entry, so the rest of the
+    /// metadata is read when it's first asked for, which can fail.
+    pub fn metadata(&self) -> io::Result<&Metadata> {
+        if let Some(metadata) = self.metadata.get() {
+            return Ok(metadata);
+        }
+        let dir_entry = self
+            .dir_entry
+            .as_ref()
+            .expect("entry has either metadata or a directory entry");
+        let metadata = dir_entry.metadata()?.into();
+        Ok(self.metadata.get_or_init(|| metadata))
+    }
+}
+
+/// The real filesystem, accessed through [std::fs].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        Ok(Box::new(fs::read_dir(path)?.map(|dir_entry| {
+            let dir_entry = dir_entry?;
+            // The file type usually comes from the directory listing, but other metadata
+            // needs a separate call on Unix, which is put off until it's needed.
+            // DirEntry::metadata doesn't follow symlinks.
+            Ok(Entry {
+                path: dir_entry.path(),
+                file_name: dir_entry.file_name(),
+                file_type: dir_entry.file_type()?.into(),
+                metadata: OnceLock::new(),
+                dir_entry: Some(Arc::new(dir_entry)),
+            })
+        })))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(Metadata::from)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(Metadata::from)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create(&self, path: &Path, mode: u32) -> io::Result<Box<dyn WriteFile>> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(Box::new(options.open(path)?))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&self, target: &Path, link: &Path, _target_is_dir: bool) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    #[cfg(windows)]
+    fn symlink(&self, target: &Path, link: &Path, target_is_dir: bool) -> io::Result<()> {
+        crate::windows::symlink(target, link, target_is_dir)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn copy_file(&self, src: &Path, dest: &Path, sync: bool) -> io::Result<u64> {
+        if !sync {
+            // fs::copy sets the permissions to match the source, and may be faster than
+            // copying through buffers.
+            return fs::copy(src, dest);
+        }
+        let mut reader = fs::File::open(src)?;
+        let permissions = reader.metadata()?.permissions();
+        let mut writer = fs::File::create(dest)?;
+        let bytes_copied = io::copy(&mut reader, &mut writer)?;
+        writer.set_permissions(permissions)?;
+        writer.sync_all()?;
+        Ok(bytes_copied)
+    }
+
+    #[cfg(unix)]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    fn set_times(
+        &self,
+        path: &Path,
+        atime: Option<SystemTime>,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        let mtime = FileTime::from_system_time(mtime);
+        match atime {
+            Some(atime) => filetime::set_file_times(path, FileTime::from_system_time(atime), mtime),
+            None => filetime::set_file_mtime(path, mtime),
+        }
+    }
+
+    fn set_symlink_times(
+        &self,
+        path: &Path,
+        atime: SystemTime,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        filetime::set_symlink_file_times(
+            path,
+            FileTime::from_system_time(atime),
+            FileTime::from_system_time(mtime),
+        )
+    }
+
+    #[cfg(unix)]
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))?;
+        if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn list_xattrs(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        crate::xattr::list(path)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_xattr(&self, path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        crate::xattr::get(path, name)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        crate::xattr::set(path, name, value)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        fs::File::open(path)?.sync_all()
+    }
+
+    /// Directories can't be synced on Windows.
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sync_filesystem(&self, path: &Path) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = fs::File::open(path)?;
+        if unsafe { libc::syncfs(file.as_raw_fd()) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Other Unix systems can only sync all filesystems.
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn sync_filesystem(&self, _path: &Path) -> io::Result<()> {
+        unsafe { libc::sync() };
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn sync_filesystem(&self, _path: &Path) -> io::Result<()> {
+        Err(unsupported("syncing a whole filesystem"))
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2024 Martin Pool
+
+//! Copy trees asynchronously, from Tokio.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::task::JoinHandle;
+
+use crate::{CopyOptions, CopyStats, Error, ErrorKind, Result};
+
+impl CopyOptions<'static> {
+    /// Copy a tree, returning a future that completes when the copy is done.
+    ///
+    /// The copy runs on Tokio's blocking thread pool, and so this must be called from
+    /// within a Tokio runtime. `make_options` is called on that blocking thread to build
+    /// the options, so the callbacks they hold need not be `Send`, but the closure
+    /// itself must be.
+    ///
+    /// The callbacks such as [filter](CopyOptions::filter) and
+    /// [after_entry_copied](CopyOptions::after_entry_copied) are the ordinary synchronous
+    /// callbacks, and they are also called on the blocking thread. They should not await
+    /// anything, but they can for example send progress to async code through a Tokio
+    /// channel.
+    ///
+    /// Dropping the future before it completes cancels the copy: it stops before
+    /// copying the next entry, leaving the destination partly copied. Awaiting the future
+    /// to completion returns the same result as [CopyOptions::copy_tree].
+    ///
+    /// This is only available with the `async` feature.
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use cp_r::CopyOptions;
+    ///
+    /// let dest = tempfile::tempdir().unwrap();
+    /// let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+    /// let copy = CopyOptions::copy_tree_async(
+    ///     move || {
+    ///         CopyOptions::new().after_entry_copied(move |path, _file_type, _stats| {
+    ///             // The receiver might have gone away; that's OK.
+    ///             let _ = progress_tx.send(path.to_owned());
+    ///             Ok(())
+    ///         })
+    ///     },
+    ///     "src",
+    ///     dest.path(),
+    /// );
+    /// let stats = copy.await.unwrap();
+    /// let mut paths_seen = 0;
+    /// while let Some(_path) = progress_rx.recv().await {
+    ///     paths_seen += 1;
+    /// }
+    /// assert_eq!(paths_seen, stats.files);
+    /// # }
+    /// ```
+    pub fn copy_tree_async<F, P, Q>(make_options: F, src: P, dest: Q) -> CopyTreeFuture
+    where
+        F: FnOnce() -> CopyOptions<'static> + Send + 'static,
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let src: PathBuf = src.as_ref().to_owned();
+        let dest: PathBuf = dest.as_ref().to_owned();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let task_cancel = Arc::clone(&cancel);
+        let join_src = src.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            CopyOptions {
+                cancel: Some(task_cancel),
+                ..make_options()
+            }
+            .copy_tree(src, dest)
+        });
+        CopyTreeFuture {
+            handle,
+            cancel,
+            src: join_src,
+        }
+    }
+}
+
+/// A future returned by [CopyOptions::copy_tree_async] that completes when the copy is
+/// finished.
+///
+/// Dropping the future cancels the copy.
+#[derive(Debug)]
+pub struct CopyTreeFuture {
+    handle: JoinHandle<Result<CopyStats>>,
+    cancel: Arc<AtomicBool>,
+    /// The source path, to describe errors if the task fails.
+    src: PathBuf,
+}
+
+impl Future for CopyTreeFuture {
+    type Output = Result<CopyStats>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.handle).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(join_error)) => match join_error.try_into_panic() {
+                // Pass panics from callbacks through to the caller.
+                Ok(panic) => std::panic::resume_unwind(panic),
+                // The runtime is shutting down.
+                Err(_) => Poll::Ready(Err(Error::new(ErrorKind::Interrupted, &self.src))),
+            },
+        }
+    }
+}
+
+impl Drop for CopyTreeFuture {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,601 @@
+// Copyright 2024 Martin Pool
+
+//! Hardened copies, which don't follow symlinks that appear inside the source or
+//! destination while they're being copied.
+//!
+//! Every path beneath the top of the source or destination is opened one component at a
+//! time, relative to the file descriptor of its parent directory, with `O_NOFOLLOW`. So,
+//! if another process replaces a directory by a symlink part way through the copy,
+//! the symlink is noticed rather than followed outside the tree.
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// The payload of an [std::io::Error] returned when a symlink was found where a
+/// directory or file was expected.
+///
+/// [Error::from_io_error](crate::Error::from_io_error) reports these as
+/// [ErrorKind::UnexpectedSymlink](crate::ErrorKind::UnexpectedSymlink).
+#[derive(Debug)]
+pub(crate) struct UnexpectedSymlink {
+    path: PathBuf,
+}
+
+impl fmt::Display for UnexpectedSymlink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is a symlink", self.path.display())
+    }
+}
+
+impl std::error::Error for UnexpectedSymlink {}
+
+/// Return true if an IO error was caused by finding an unexpected symlink.
+pub(crate) fn is_unexpected_symlink(io: &std::io::Error) -> bool {
+    io.get_ref()
+        .is_some_and(|err| err.is::<UnexpectedSymlink>())
+}
+
+#[cfg(unix)]
+pub(crate) use self::unix::HardenedFileSystem;
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::{CStr, CString, OsStr, OsString};
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read};
+    use std::mem::MaybeUninit;
+    use std::os::raw::{c_int, c_long, c_uint};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Component, Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    use filetime::FileTime;
+
+    use super::UnexpectedSymlink;
+    use crate::filesystem::system_time;
+    use crate::{Entry, FileSystem, FileType, Metadata, ReadDir, StdFileSystem, WriteFile};
+
+    /// A filesystem that resolves paths beneath some trusted roots without following
+    /// symlinks.
+    ///
+    /// Paths outside all the roots, such as the parent of the destination, are accessed
+    /// through [StdFileSystem].
+    pub(crate) struct HardenedFileSystem {
+        roots: Vec<Root>,
+    }
+
+    struct Root {
+        path: PathBuf,
+        /// The root directory, opened when it's first needed, because the destination
+        /// might not exist yet.
+        dir: Mutex<Option<Arc<File>>>,
+    }
+
+    impl Root {
+        fn dir(&self) -> io::Result<Arc<File>> {
+            let mut dir = self.dir.lock().unwrap();
+            if let Some(dir) = &*dir {
+                return Ok(Arc::clone(dir));
+            }
+            // The root itself is trusted, so symlinks leading to it are followed.
+            let file = Arc::new(
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_DIRECTORY)
+                    .open(&self.path)?,
+            );
+            *dir = Some(Arc::clone(&file));
+            Ok(file)
+        }
+    }
+
+    /// A path resolved to a directory and a name within it.
+    ///
+    /// The top of a root is resolved to the root directory and `.`.
+    struct At {
+        dir: Arc<File>,
+        name: CString,
+        path: PathBuf,
+    }
+
+    impl HardenedFileSystem {
+        pub(crate) fn new(roots: &[&Path]) -> HardenedFileSystem {
+            HardenedFileSystem {
+                roots: roots
+                    .iter()
+                    .map(|path| Root {
+                        path: path.to_path_buf(),
+                        dir: Mutex::new(None),
+                    })
+                    .collect(),
+            }
+        }
+
+        /// Find the directory that contains a path, opening each directory beneath the
+        /// root without following symlinks.
+        ///
+        /// Returns None if the path is not inside any of the roots.
+        fn resolve(&self, path: &Path) -> io::Result<Option<At>> {
+            // If one root is inside another, the innermost one is used.
+            let root = match self
+                .roots
+                .iter()
+                .filter(|root| path.starts_with(&root.path))
+                .max_by_key(|root| root.path.components().count())
+            {
+                Some(root) => root,
+                None => return Ok(None),
+            };
+            let mut names = Vec::new();
+            for component in path.strip_prefix(&root.path).unwrap().components() {
+                match component {
+                    Component::Normal(name) => names.push(name),
+                    Component::CurDir => (),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("path is not beneath {}", root.path.display()),
+                        ))
+                    }
+                }
+            }
+            let mut at = At {
+                dir: root.dir()?,
+                name: c_name(OsStr::new("."))?,
+                path: root.path.clone(),
+            };
+            let last = names.pop();
+            for name in names {
+                at.name = c_name(name)?;
+                at.path.push(name);
+                let dir = at.open(libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?;
+                at.dir = Arc::new(dir);
+            }
+            if let Some(name) = last {
+                at.name = c_name(name)?;
+                at.path.push(name);
+            }
+            Ok(Some(at))
+        }
+    }
+
+    impl At {
+        fn is_top(&self) -> bool {
+            self.name.as_bytes() == b"."
+        }
+
+        /// Open the entry, with `O_CLOEXEC` added to the flags.
+        fn open(&self, flags: c_int, mode: u32) -> io::Result<File> {
+            let fd = unsafe {
+                libc::openat(
+                    self.dir.as_raw_fd(),
+                    self.name.as_ptr(),
+                    flags | libc::O_CLOEXEC,
+                    mode as c_uint,
+                )
+            };
+            if fd == -1 {
+                Err(self.symlink_error(io::Error::last_os_error()))
+            } else {
+                Ok(unsafe { File::from_raw_fd(fd) })
+            }
+        }
+
+        /// Set the mode of the entry, without opening it, which might not be allowed by
+        /// its current mode, and without following a symlink.
+        fn chmod(&self, mode: u32) -> io::Result<()> {
+            let chmod = |flags| {
+                cvt(unsafe {
+                    libc::fchmodat(
+                        self.dir.as_raw_fd(),
+                        self.name.as_ptr(),
+                        mode as libc::mode_t,
+                        flags,
+                    )
+                })
+            };
+            match chmod(libc::AT_SYMLINK_NOFOLLOW) {
+                // Linux can't set the mode of a symlink, and older C libraries don't
+                // support the flag at all, so check for a symlink before following it.
+                Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                    let st = stat_at(&self.dir, &self.name, libc::AT_SYMLINK_NOFOLLOW)?;
+                    if st.st_mode & libc::S_IFMT == libc::S_IFLNK {
+                        Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            UnexpectedSymlink {
+                                path: self.path.clone(),
+                            },
+                        ))
+                    } else {
+                        chmod(0)
+                    }
+                }
+                result => result,
+            }
+        }
+
+        fn stat(&self, flags: c_int) -> io::Result<Metadata> {
+            stat_at(&self.dir, &self.name, flags).map(|st| metadata_from_stat(&st))
+        }
+
+        /// If opening the entry with `O_NOFOLLOW` failed because it's a symlink, say so.
+        fn symlink_error(&self, err: io::Error) -> io::Error {
+            if matches!(err.raw_os_error(), Some(libc::ELOOP) | Some(libc::ENOTDIR))
+                && stat_at(&self.dir, &self.name, libc::AT_SYMLINK_NOFOLLOW)
+                    .is_ok_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFLNK)
+            {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    UnexpectedSymlink {
+                        path: self.path.clone(),
+                    },
+                )
+            } else {
+                err
+            }
+        }
+    }
+
+    impl FileSystem for HardenedFileSystem {
+        fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+            let at = match self.resolve(path)? {
+                Some(at) => at,
+                None => return StdFileSystem.read_dir(path),
+            };
+            let dir = at.open(libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?;
+            let names = read_names(&dir)?;
+            // The entries are all listed up front, so that the metadata is read relative
+            // to the same directory.
+            let entries: Vec<io::Result<Entry>> = names
+                .into_iter()
+                .map(|name| {
+                    let st = stat_at(&dir, &name, libc::AT_SYMLINK_NOFOLLOW)?;
+                    let path = path.join(OsStr::from_bytes(name.as_bytes()));
+                    Ok(Entry::new(path, metadata_from_stat(&st)))
+                })
+                .collect();
+            Ok(Box::new(entries.into_iter()))
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+            match self.resolve(path)? {
+                Some(at) => at.stat(0),
+                None => StdFileSystem.metadata(path),
+            }
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+            match self.resolve(path)? {
+                Some(at) => at.stat(libc::AT_SYMLINK_NOFOLLOW),
+                None => StdFileSystem.symlink_metadata(path),
+            }
+        }
+
+        fn create_dir(&self, path: &Path) -> io::Result<()> {
+            match self.resolve(path) {
+                Ok(Some(at)) if !at.is_top() => {
+                    cvt(unsafe { libc::mkdirat(at.dir.as_raw_fd(), at.name.as_ptr(), 0o777) })
+                }
+                // The top of the destination is trusted, and can't be opened until it exists.
+                Ok(_) => StdFileSystem.create_dir(path),
+                Err(err) if err.kind() == io::ErrorKind::NotFound && self.is_root(path) => {
+                    StdFileSystem.create_dir(path)
+                }
+                Err(err) => Err(err),
+            }
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+            match self.resolve(path)? {
+                Some(at) => Ok(Box::new(at.open(libc::O_RDONLY | libc::O_NOFOLLOW, 0)?)),
+                None => StdFileSystem.open(path),
+            }
+        }
+
+        fn create(&self, path: &Path, mode: u32) -> io::Result<Box<dyn WriteFile>> {
+            match self.resolve(path)? {
+                Some(at) => Ok(Box::new(at.open(
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW,
+                    mode,
+                )?)),
+                None => StdFileSystem.create(path, mode),
+            }
+        }
+
+        fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+            let at = match self.resolve(path)? {
+                Some(at) => at,
+                None => return StdFileSystem.read_link(path),
+            };
+            let mut buf: Vec<u8> = Vec::with_capacity(256);
+            loop {
+                let len = unsafe {
+                    libc::readlinkat(
+                        at.dir.as_raw_fd(),
+                        at.name.as_ptr(),
+                        buf.as_mut_ptr().cast(),
+                        buf.capacity(),
+                    )
+                };
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // If the buffer was filled, the target might have been truncated.
+                if (len as usize) < buf.capacity() {
+                    unsafe { buf.set_len(len as usize) };
+                    return Ok(PathBuf::from(OsString::from_vec(buf)));
+                }
+                buf.reserve(buf.capacity() * 2);
+            }
+        }
+
+        fn symlink(&self, target: &Path, link: &Path, target_is_dir: bool) -> io::Result<()> {
+            let at = match self.resolve(link)? {
+                Some(at) => at,
+                None => return StdFileSystem.symlink(target, link, target_is_dir),
+            };
+            let target = c_name(target.as_os_str())?;
+            cvt(unsafe { libc::symlinkat(target.as_ptr(), at.dir.as_raw_fd(), at.name.as_ptr()) })
+        }
+
+        fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+            let link_at = match self.resolve(link)? {
+                Some(at) => at,
+                None => return StdFileSystem.hard_link(original, link),
+            };
+            // The original is normally in a reference tree outside the roots, which is
+            // trusted like the roots themselves. The original itself isn't followed if
+            // it's a symlink.
+            let original_at = self.resolve(original)?;
+            let (original_dir, original_name) = match &original_at {
+                Some(at) => (at.dir.as_raw_fd(), at.name.clone()),
+                None => (libc::AT_FDCWD, c_name(original.as_os_str())?),
+            };
+            cvt(unsafe {
+                libc::linkat(
+                    original_dir,
+                    original_name.as_ptr(),
+                    link_at.dir.as_raw_fd(),
+                    link_at.name.as_ptr(),
+                    0,
+                )
+            })
+        }
+
+        fn copy_file(&self, src: &Path, dest: &Path, sync: bool) -> io::Result<u64> {
+            let (src_at, dest_at) = match (self.resolve(src)?, self.resolve(dest)?) {
+                (Some(src_at), Some(dest_at)) => (src_at, dest_at),
+                _ => return StdFileSystem.copy_file(src, dest, sync),
+            };
+            let mut reader = src_at.open(libc::O_RDONLY | libc::O_NOFOLLOW, 0)?;
+            let mode = reader.metadata()?.permissions().mode() & 0o7777;
+            let mut writer = dest_at.open(
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW,
+                0o600,
+            )?;
+            let bytes = io::copy(&mut reader, &mut writer)?;
+            writer.set_permissions(fs::Permissions::from_mode(mode))?;
+            if sync {
+                writer.sync_all()?;
+            }
+            Ok(bytes)
+        }
+
+        fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+            match self.resolve(path)? {
+                Some(at) => at.chmod(mode),
+                None => StdFileSystem.set_permissions(path, mode),
+            }
+        }
+
+        fn set_times(
+            &self,
+            path: &Path,
+            atime: Option<SystemTime>,
+            mtime: SystemTime,
+        ) -> io::Result<()> {
+            match self.resolve(path)? {
+                Some(at) => {
+                    let atime = match atime {
+                        Some(atime) => timespec(atime),
+                        None => libc::timespec {
+                            tv_sec: 0,
+                            tv_nsec: libc::UTIME_OMIT,
+                        },
+                    };
+                    set_times_at(&at, atime, timespec(mtime))
+                }
+                None => StdFileSystem.set_times(path, atime, mtime),
+            }
+        }
+
+        fn set_symlink_times(
+            &self,
+            path: &Path,
+            atime: SystemTime,
+            mtime: SystemTime,
+        ) -> io::Result<()> {
+            match self.resolve(path)? {
+                Some(at) => set_times_at(&at, timespec(atime), timespec(mtime)),
+                None => StdFileSystem.set_symlink_times(path, atime, mtime),
+            }
+        }
+
+        fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+            match self.resolve(path)? {
+                Some(at) => cvt(unsafe {
+                    libc::fchownat(
+                        at.dir.as_raw_fd(),
+                        at.name.as_ptr(),
+                        uid,
+                        gid,
+                        libc::AT_SYMLINK_NOFOLLOW,
+                    )
+                }),
+                None => StdFileSystem.set_owner(path, uid, gid),
+            }
+        }
+
+        fn sync_dir(&self, path: &Path) -> io::Result<()> {
+            match self.resolve(path)? {
+                Some(at) => at
+                    .open(libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?
+                    .sync_all(),
+                None => StdFileSystem.sync_dir(path),
+            }
+        }
+
+        fn sync_filesystem(&self, path: &Path) -> io::Result<()> {
+            StdFileSystem.sync_filesystem(path)
+        }
+    }
+
+    impl HardenedFileSystem {
+        fn is_root(&self, path: &Path) -> bool {
+            self.roots.iter().any(|root| root.path == path)
+        }
+    }
+
+    fn cvt(ret: c_int) -> io::Result<()> {
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn c_name(name: &OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))
+    }
+
+    fn stat_at(dir: &File, name: &CStr, flags: c_int) -> io::Result<libc::stat> {
+        let mut st = MaybeUninit::<libc::stat>::uninit();
+        cvt(unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), st.as_mut_ptr(), flags) })?;
+        Ok(unsafe { st.assume_init() })
+    }
+
+    fn set_times_at(at: &At, atime: libc::timespec, mtime: libc::timespec) -> io::Result<()> {
+        let times = [atime, mtime];
+        cvt(unsafe {
+            libc::utimensat(
+                at.dir.as_raw_fd(),
+                at.name.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })
+    }
+
+    fn timespec(time: SystemTime) -> libc::timespec {
+        let time = FileTime::from_system_time(time);
+        libc::timespec {
+            tv_sec: time.unix_seconds() as libc::time_t,
+            tv_nsec: time.nanoseconds() as c_long,
+        }
+    }
+
+    // The types of the stat fields vary between platforms.
+    #[allow(clippy::useless_conversion, clippy::unnecessary_cast)]
+    fn metadata_from_stat(st: &libc::stat) -> Metadata {
+        let file_type = match st.st_mode & libc::S_IFMT {
+            libc::S_IFREG => FileType::File,
+            libc::S_IFDIR => FileType::Dir,
+            libc::S_IFLNK => FileType::Symlink,
+            _ => FileType::Other,
+        };
+        let time = |secs: libc::time_t, nanos: c_long| {
+            system_time(FileTime::from_unix_time(i64::from(secs), nanos as u32))
+        };
+        Metadata {
+            file_type,
+            len: st.st_size as u64,
+            mode: u32::from(st.st_mode) & 0o7777,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            atime: time(st.st_atime, st.st_atime_nsec),
+            mtime: time(st.st_mtime, st.st_mtime_nsec),
+            dev: st.st_dev as u64,
+            ino: st.st_ino as u64,
+        }
+    }
+
+    /// Read the names in an open directory, other than `.` and `..`.
+    fn read_names(dir: &File) -> io::Result<Vec<CString>> {
+        // fdopendir takes ownership of the descriptor that it's given.
+        let fd = unsafe { libc::dup(dir.as_raw_fd()) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let dirp = unsafe { libc::fdopendir(fd) };
+        if dirp.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        let mut names = Vec::new();
+        let result = loop {
+            clear_errno();
+            let dirent = unsafe { libc::readdir(dirp) };
+            if dirent.is_null() {
+                let err = io::Error::last_os_error();
+                break match err.raw_os_error() {
+                    Some(0) | None => Ok(names),
+                    Some(_) => Err(err),
+                };
+            }
+            let name = unsafe { CStr::from_ptr((*dirent).d_name.as_ptr()) };
+            if name.to_bytes() != b"." && name.to_bytes() != b".." {
+                names.push(name.to_owned());
+            }
+        };
+        unsafe { libc::closedir(dirp) };
+        result
+    }
+
+    /// Clear errno, so that the end of a directory can be told apart from an error.
+    fn clear_errno() {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "emscripten",
+            target_os = "hurd",
+            target_os = "redox",
+        ))]
+        let errno = unsafe { libc::__errno_location() };
+        #[cfg(any(
+            target_os = "android",
+            target_os = "cygwin",
+            target_os = "netbsd",
+            target_os = "openbsd",
+        ))]
+        let errno = unsafe { libc::__errno() };
+        #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+        let errno = unsafe { libc::__error() };
+        #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+        let errno = unsafe { libc::___errno() };
+        #[cfg(target_os = "haiku")]
+        let errno = unsafe { libc::_errnop() };
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "emscripten",
+            target_os = "hurd",
+            target_os = "redox",
+            target_os = "android",
+            target_os = "cygwin",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_vendor = "apple",
+            target_os = "freebsd",
+            target_os = "illumos",
+            target_os = "solaris",
+            target_os = "haiku",
+        )))]
+        compile_error!("hardened copies don't know how to clear errno on this platform");
+        unsafe { *errno = 0 };
+    }
+}
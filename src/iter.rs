@@ -0,0 +1,764 @@
+// Copyright 2024 Martin Pool
+
+//! Copy a tree one entry at a time, as an iterator of events.
+
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "tar")]
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use crate::*;
+
+/// Something that happened while copying a tree, returned by [CopyIter].
+///
+/// Paths are relative to the top of the tree.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CopyEvent {
+    /// A directory was created.
+    ///
+    /// If the destination directory itself is created, this is reported with an
+    /// empty path.
+    DirCreated {
+        /// The path of the directory.
+        path: PathBuf,
+    },
+    /// A source directory was mapped by [CopyOptions::map_path] onto a directory that
+    /// already exists in the destination, so its contents are merged into that one.
+    DirMerged {
+        /// The path of the source directory.
+        path: PathBuf,
+    },
+    /// A file was copied.
+    FileCopied {
+        /// The path of the file.
+        path: PathBuf,
+        /// The number of bytes of content copied.
+        bytes: u64,
+    },
+    /// A file was hard linked to the same file in the reference tree set by
+    /// [CopyOptions::link_dest], rather than copied.
+    FileLinked {
+        /// The path of the file.
+        path: PathBuf,
+    },
+    /// A symlink was created.
+    SymlinkCreated {
+        /// The path of the symlink.
+        path: PathBuf,
+    },
+    /// An entry was skipped by the [CopyOptions::filter] callback.
+    FilteredOut {
+        /// The path of the entry.
+        path: PathBuf,
+    },
+    /// An entry was skipped because of a [Limit] on its depth or size.
+    SkippedByLimit {
+        /// The path of the entry.
+        path: PathBuf,
+        /// The limit that caused it to be skipped.
+        limit: Limit,
+    },
+    /// A directory was skipped because it's on a different filesystem from the source,
+    /// as set by [CopyOptions::one_file_system].
+    OtherFileSystem {
+        /// The path of the directory.
+        path: PathBuf,
+    },
+    /// Copying failed.
+    ///
+    /// This is always the last event.
+    Error(Error),
+}
+
+/// An iterator that copies a tree, returned by [CopyOptions::into_iter].
+///
+/// Each call to [Iterator::next] copies one more entry and returns a [CopyEvent]
+/// describing it. The iteration stops after the whole tree is copied, or after
+/// returning [CopyEvent::Error].
+///
+/// The [CopyOptions::filter] and [CopyOptions::after_entry_copied] callbacks, if set,
+/// are still called.
+///
+/// Some work is done after all the entries are copied, such as setting directory
+/// permissions and syncing: if the iterator is dropped before it returns `None` this
+/// is not done.
+pub struct CopyIter<'f> {
+    options: CopyOptions<'f>,
+    src: PathBuf,
+    dest: PathBuf,
+    stats: CopyStats,
+    /// When the copy started, if it has.
+    start_time: Option<Instant>,
+    finished: bool,
+    /// The device id of the source directory, if the copy stays on one filesystem.
+    src_dev: Option<u64>,
+    /// The device and inode of the destination directory, if known, to check that the
+    /// walk doesn't reach it.
+    dest_id: Option<(u64, u64)>,
+    /// The device and inode of the top source directory, if known.
+    src_id: Option<(u64, u64)>,
+    /// Source directories, relative to the top of the tree, that have yet to be read,
+    /// with the ids of each directory and its parents, as in [OpenDir::ids].
+    subdir_queue: VecDeque<(PathBuf, Vec<(u64, u64)>)>,
+    /// The destination paths chosen by [CopyOptions::map_path] so far, relative to the
+    /// top of the destination, to detect collisions.
+    dest_paths: HashMap<PathBuf, Kind>,
+    /// The directories currently being read, relative to the top of the tree, and their
+    /// remaining entries, innermost last.
+    ///
+    /// A breadth-first walk reads one directory at a time; a depth-first walk has one
+    /// for each level it's inside.
+    dir_stack: Vec<OpenDir>,
+    /// Directory permissions are set after everything else is copied, in case they're
+    /// not writable.
+    dir_modes: Vec<(PathBuf, u32)>,
+    /// Directories whose entries should be synced at the end.
+    sync_dirs: Vec<PathBuf>,
+    /// If set, entries are written to this archive rather than to `dest`.
+    #[cfg(feature = "tar")]
+    tar: Option<tar::Builder<Box<dyn Write + 'f>>>,
+    /// A span covering the whole copy.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// A source directory that's being read.
+struct OpenDir {
+    /// The path of the directory relative to the top of the tree.
+    path: PathBuf,
+    /// The remaining entries.
+    entries: ReadDir,
+    /// The device and inode of this directory and each of its parents, where known, to
+    /// detect cycles.
+    ids: Vec<(u64, u64)>,
+    /// A span covering copying the entries of this directory.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl<'f> CopyIter<'f> {
+    pub(crate) fn new(options: CopyOptions<'f>, src: PathBuf, dest: PathBuf) -> CopyIter<'f> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "copy_tree",
+            src = %src.display(),
+            dest = %dest.display()
+        );
+        CopyIter {
+            options,
+            src,
+            dest,
+            stats: CopyStats::default(),
+            start_time: None,
+            finished: false,
+            src_dev: None,
+            dest_id: None,
+            src_id: None,
+            dest_paths: HashMap::new(),
+            subdir_queue: VecDeque::new(),
+            dir_stack: Vec::new(),
+            dir_modes: Vec::new(),
+            sync_dirs: Vec::new(),
+            #[cfg(feature = "tar")]
+            tar: None,
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+
+    /// Construct an iterator that writes the tree into a tar archive.
+    #[cfg(feature = "tar")]
+    pub(crate) fn new_tar(
+        options: CopyOptions<'f>,
+        src: PathBuf,
+        builder: tar::Builder<Box<dyn Write + 'f>>,
+    ) -> CopyIter<'f> {
+        CopyIter {
+            tar: Some(builder),
+            ..CopyIter::new(options, src, PathBuf::new())
+        }
+    }
+
+    /// The stats of everything copied so far.
+    pub fn stats(&self) -> &CopyStats {
+        &self.stats
+    }
+
+    /// Consume the iterator and return the stats of everything copied.
+    pub fn into_stats(self) -> CopyStats {
+        self.stats
+    }
+
+    fn sync_dirs_enabled(&self) -> bool {
+        self.options.sync == SyncMode::FilesAndDirs
+    }
+
+    fn sync_files_enabled(&self) -> bool {
+        matches!(self.options.sync, SyncMode::Files | SyncMode::FilesAndDirs)
+    }
+
+    /// Copy the next entry, or return None if everything is copied.
+    fn step(&mut self) -> Result<Option<CopyEvent>> {
+        if self.start_time.is_none() {
+            self.start_time = Some(Instant::now());
+            if let Some(event) = self.start()? {
+                #[cfg(feature = "tracing")]
+                trace_event(&event);
+                return Ok(Some(event));
+            }
+        }
+        loop {
+            // In a depth-first walk, the queue holds at most the directory that was just
+            // created, which is read before the rest of its parent.
+            if self.dir_stack.is_empty() || self.options.traversal == Traversal::DepthFirst {
+                if let Some((subdir, ids)) = self.subdir_queue.pop_front() {
+                    let entries = self.read_dir(&subdir)?;
+                    self.dir_stack.push(OpenDir {
+                        #[cfg(feature = "tracing")]
+                        span: tracing::debug_span!("copy_dir", path = %subdir.display()),
+                        path: subdir,
+                        entries,
+                        ids,
+                    });
+                    continue;
+                }
+            }
+            if let Some(open_dir) = self.dir_stack.last_mut() {
+                #[cfg(feature = "tracing")]
+                let _span = open_dir.span.clone().entered();
+                let read_start = Instant::now();
+                let next = open_dir.entries.next();
+                self.stats.read_dir_time += read_start.elapsed();
+                match next {
+                    Some(entry) => {
+                        let src = &self.src;
+                        let subdir = &open_dir.path;
+                        let entry = entry.map_err(|io| {
+                            Error::from_io_error(io, ErrorKind::ReadDir, src.join(subdir))
+                        })?;
+                        let entry_subpath = subdir.join(entry.file_name());
+                        let event = self.copy_entry(entry_subpath, &entry)?;
+                        #[cfg(feature = "tracing")]
+                        trace_event(&event);
+                        return Ok(Some(event));
+                    }
+                    None => {
+                        self.dir_stack.pop();
+                    }
+                }
+            } else {
+                self.finish()?;
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Start reading a source directory, sorting its entries if requested.
+    fn read_dir(&mut self, subdir: &Path) -> Result<ReadDir> {
+        let read_start = Instant::now();
+        let subdir_full_path = self.src.join(subdir);
+        let read_err = |io| Error::from_io_error(io, ErrorKind::ReadDir, &subdir_full_path);
+        let read_dir = self
+            .options
+            .file_system
+            .read_dir(&subdir_full_path)
+            .map_err(read_err)?;
+        let read_dir: ReadDir = match &mut self.options.sort {
+            Some(compare) => {
+                let mut entries = read_dir
+                    .collect::<io::Result<Vec<Entry>>>()
+                    .map_err(read_err)?;
+                entries.sort_by(|a, b| compare(a, b));
+                Box::new(entries.into_iter().map(Ok))
+            }
+            None => read_dir,
+        };
+        self.stats.read_dir_time += read_start.elapsed();
+        Ok(read_dir)
+    }
+
+    /// Update the elapsed time in the stats.
+    fn update_elapsed(&mut self) {
+        if let Some(start_time) = self.start_time {
+            self.stats.elapsed = start_time.elapsed();
+        }
+    }
+
+    /// Create the destination if necessary, and start walking the source.
+    fn start(&mut self) -> Result<Option<CopyEvent>> {
+        let src = self.src.clone();
+        let dest = self.dest.clone();
+        self.src_id = self.file_id(&src);
+        if self.options.one_file_system {
+            let src_metadata = self
+                .options
+                .file_system
+                .metadata(&src)
+                .map_err(|io| Error::from_io_error(io, ErrorKind::ReadDir, &src))?;
+            self.src_dev = Some(src_metadata.dev);
+        }
+        #[cfg(feature = "tar")]
+        if self.tar.is_some() {
+            self.options.harden(&[&src], ErrorKind::ReadDir)?;
+            self.queue_top_dir();
+            return Ok(None);
+        }
+        // Check before creating anything that the destination isn't the source or
+        // inside it, which would copy the tree into itself until the disk fills.
+        if let Some(src_id) = self.src_id {
+            if self.is_inside(&dest, src_id) {
+                return Err(Error::new(ErrorKind::DestinationInsideSource, dest));
+            }
+        }
+        self.options.harden(&[&src, &dest], ErrorKind::ReadDir)?;
+        let mut event = None;
+        // TODO: Handle the src not being a dir: copy that single entry.
+        let dest_is_dir = self
+            .options
+            .file_system
+            .metadata(&dest)
+            .is_ok_and(|m| m.file_type.is_dir());
+        if self.options.create_destination {
+            if !dest_is_dir {
+                copy_dir(&*self.options.file_system, &dest, &mut self.stats)?;
+                let src_metadata = self
+                    .options
+                    .file_system
+                    .metadata(&src)
+                    .map_err(|io| Error::from_io_error(io, ErrorKind::ReadDir, &src))?;
+                let owner = self.options.dest_owner(&src_metadata);
+                self.options.copy_entry_metadata(
+                    &src,
+                    &dest,
+                    Kind::Dir,
+                    &src_metadata,
+                    owner,
+                    &mut self.stats,
+                )?;
+                if let Some(dir_mode) = self.options.dir_mode {
+                    self.dir_modes
+                        .push((dest.clone(), dir_mode.apply(src_metadata.mode)));
+                }
+                if self.sync_dirs_enabled() {
+                    if let Some(parent) = dest.parent() {
+                        self.sync_dirs.push(parent.to_owned());
+                    }
+                }
+                event = Some(CopyEvent::DirCreated {
+                    path: PathBuf::new(),
+                });
+            }
+        } else if !dest_is_dir {
+            return Err(Error::new(ErrorKind::DestinationDoesNotExist, dest));
+        }
+        // The destination could still be reached in ways that don't show in its path,
+        // such as through a bind mount, so also look for it during the walk.
+        self.dest_id = self.file_id(&dest);
+        if self.sync_dirs_enabled() {
+            self.sync_dirs.push(dest);
+        }
+        self.queue_top_dir();
+        Ok(event)
+    }
+
+    /// Queue the top source directory to be read.
+    fn queue_top_dir(&mut self) {
+        let ids = self.src_id.into_iter().collect();
+        self.subdir_queue.push_back((PathBuf::new(), ids));
+    }
+
+    /// Return the device and inode of a directory, following symlinks, if they're known.
+    fn file_id(&self, path: &Path) -> Option<(u64, u64)> {
+        match self.options.file_system.metadata(path) {
+            Ok(metadata) if metadata.ino != 0 => Some((metadata.dev, metadata.ino)),
+            _ => None,
+        }
+    }
+
+    /// Return true if `path`, or the nearest existing directory above it, is the directory
+    /// with id `dir_id` or inside it.
+    fn is_inside(&self, path: &Path, dir_id: (u64, u64)) -> bool {
+        // Start from the deepest part of the path that exists, and then walk up through
+        // the real parents of each directory, so that `..` and symlinks are followed as
+        // the filesystem sees them, rather than by removing components from the path.
+        let (mut path, mut id) = match path.ancestors().find_map(|ancestor| {
+            let ancestor = if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            };
+            self.file_id(ancestor).map(|id| (ancestor.to_owned(), id))
+        }) {
+            Some(found) => found,
+            None => return false,
+        };
+        loop {
+            if id == dir_id {
+                return true;
+            }
+            // Filesystems that don't understand `..` have paths without it, whose parents
+            // can be found by removing the last component.
+            let dotdot = path.join("..");
+            let parent = match self.file_id(&dotdot) {
+                Some(parent_id) => (dotdot, parent_id),
+                None => match path
+                    .parent()
+                    .and_then(|p| self.file_id(p).map(|id| (p, id)))
+                {
+                    Some((parent, parent_id)) => (parent.to_owned(), parent_id),
+                    None => return false,
+                },
+            };
+            // The root is its own parent.
+            if parent.1 == id {
+                return false;
+            }
+            (path, id) = parent;
+        }
+    }
+
+    /// Copy one entry from the source.
+    fn copy_entry(&mut self, entry_subpath: PathBuf, entry: &Entry) -> Result<CopyEvent> {
+        if let Some(cancel) = &self.options.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(Error::new(ErrorKind::Interrupted, entry.path()));
+            }
+        }
+        if entry.file_type().is_dir() && self.dest_id.is_some() {
+            let metadata = entry_metadata(entry)?;
+            if self.dest_id == Some((metadata.dev, metadata.ino)) {
+                return Err(Error::new(ErrorKind::DestinationInsideSource, entry.path()));
+            }
+        }
+        if let Some(filter) = &mut self.options.filter {
+            if !filter(&entry_subpath, entry)? {
+                self.stats.filtered_out += 1;
+                return Ok(CopyEvent::FilteredOut {
+                    path: entry_subpath,
+                });
+            }
+        }
+        let metadata = entry_metadata(entry)?;
+        if let Some(src_dev) = self.src_dev {
+            if entry.file_type().is_dir() && metadata.dev != src_dev {
+                self.stats.other_filesystem_dirs += 1;
+                return Ok(CopyEvent::OtherFileSystem {
+                    path: entry_subpath,
+                });
+            }
+        }
+        if let Some(limit) = self
+            .options
+            .check_limits(&entry_subpath, entry, &mut self.stats)?
+        {
+            return Ok(CopyEvent::SkippedByLimit {
+                path: entry_subpath,
+                limit,
+            });
+        }
+        let src_fullpath = self.src.join(&entry_subpath);
+        let file_type = entry.file_type();
+        let mut dir_ids = Vec::new();
+        if file_type.is_dir() {
+            dir_ids = self
+                .dir_stack
+                .last()
+                .expect("entries are read from an open directory")
+                .ids
+                .clone();
+            if metadata.ino != 0 {
+                let id = (metadata.dev, metadata.ino);
+                // A directory that's the same as one of its parents, for example through a
+                // bind mount, would be copied forever.
+                if dir_ids.contains(&id) {
+                    return Err(Error::new(ErrorKind::Cycle, src_fullpath));
+                }
+                dir_ids.push(id);
+            }
+        }
+        let kind = match file_type {
+            FileType::File => Kind::File,
+            FileType::Dir => Kind::Dir,
+            FileType::Symlink => Kind::Symlink,
+            // TODO: Include the file type.
+            FileType::Other => {
+                return Err(Error::new(ErrorKind::UnsupportedFileType, src_fullpath))
+            }
+        };
+        let (dest_subpath, merged) =
+            match self
+                .options
+                .map_dest_path(&entry_subpath, entry, kind, &mut self.dest_paths)?
+            {
+                Some(mapped) => mapped,
+                None => {
+                    self.stats.filtered_out += 1;
+                    return Ok(CopyEvent::FilteredOut {
+                        path: entry_subpath,
+                    });
+                }
+            };
+        #[cfg(feature = "tar")]
+        let bytes = if merged {
+            Some(0)
+        } else if let Some(builder) = &mut self.tar {
+            Some(self.options.append_to_tar(
+                builder,
+                &src_fullpath,
+                &entry_subpath,
+                &dest_subpath,
+                kind,
+                entry,
+                &mut self.stats,
+            )?)
+        } else {
+            self.copy_to_dest(&src_fullpath, &entry_subpath, &dest_subpath, kind, entry)?
+        };
+        #[cfg(not(feature = "tar"))]
+        let bytes = if merged {
+            Some(0)
+        } else {
+            self.copy_to_dest(&src_fullpath, &entry_subpath, &dest_subpath, kind, entry)?
+        };
+        if kind == Kind::Dir {
+            self.subdir_queue
+                .push_back((entry_subpath.clone(), dir_ids));
+        }
+        self.update_elapsed();
+        if let Some(ref mut f) = self.options.after_entry_copied {
+            f(&entry_subpath, &file_type, &self.stats)?;
+        }
+        let path = entry_subpath;
+        Ok(match kind {
+            Kind::File => match bytes {
+                Some(bytes) => CopyEvent::FileCopied { path, bytes },
+                None => CopyEvent::FileLinked { path },
+            },
+            Kind::Dir if merged => CopyEvent::DirMerged { path },
+            Kind::Dir => CopyEvent::DirCreated { path },
+            Kind::Symlink => CopyEvent::SymlinkCreated { path },
+        })
+    }
+
+    /// Copy one entry into the destination directory, at `dest_subpath` relative to the
+    /// top of the destination, returning the number of bytes copied, or None if a file
+    /// was hard linked to the reference tree rather than copied.
+    fn copy_to_dest(
+        &mut self,
+        src_fullpath: &Path,
+        entry_subpath: &Path,
+        dest_subpath: &Path,
+        kind: Kind,
+        entry: &Entry,
+    ) -> Result<Option<u64>> {
+        let dest_fullpath = self.dest.join(dest_subpath);
+        // The metadata was read when listing the directory, so the atime is not yet
+        // updated by reading the file.
+        let src_metadata = entry_metadata(entry)?;
+        let owner = self.options.dest_owner(src_metadata);
+        let transform = self.options.should_transform(entry_subpath, entry);
+        if kind == Kind::File
+            && !transform
+            && self.link_to_reference(
+                src_fullpath,
+                dest_subpath,
+                &dest_fullpath,
+                src_metadata,
+                owner,
+            )
+        {
+            // The link shares the reference's metadata, which must not be changed.
+            self.stats.linked_files += 1;
+            return Ok(None);
+        }
+        self.options.check_total_limits(entry, &self.stats)?;
+        let fs = &*self.options.file_system;
+        let bytes = match kind {
+            Kind::File => {
+                let sync = self.sync_files_enabled();
+                let copy_start = Instant::now();
+                let bytes = if transform {
+                    write_transformed_file(
+                        &mut self.options,
+                        entry_subpath,
+                        src_fullpath,
+                        &dest_fullpath,
+                        src_metadata.mode,
+                        sync,
+                        &mut self.stats,
+                    )?
+                } else {
+                    copy_file(
+                        fs,
+                        src_fullpath,
+                        &dest_fullpath,
+                        self.options.file_mode,
+                        sync,
+                    )?
+                };
+                self.stats.copy_file_time += copy_start.elapsed();
+                self.stats.add_file(entry_subpath, bytes);
+                bytes
+            }
+            Kind::Dir => {
+                copy_dir(fs, &dest_fullpath, &mut self.stats)?;
+                if let Some(dir_mode) = self.options.dir_mode {
+                    self.dir_modes
+                        .push((dest_fullpath.clone(), dir_mode.apply(src_metadata.mode)));
+                }
+                if self.sync_dirs_enabled() {
+                    self.sync_dirs.push(dest_fullpath.clone());
+                }
+                0
+            }
+            Kind::Symlink => {
+                copy_symlink(fs, src_fullpath, &dest_fullpath, &mut self.stats)?;
+                0
+            }
+        };
+        self.options.copy_entry_metadata(
+            src_fullpath,
+            &dest_fullpath,
+            kind,
+            src_metadata,
+            owner,
+            &mut self.stats,
+        )?;
+        Ok(Some(bytes))
+    }
+
+    /// Hard link a file to the identical file at the same path in the
+    /// [CopyOptions::link_dest] reference tree, if there is one, returning true if it was
+    /// linked.
+    fn link_to_reference(
+        &mut self,
+        src_fullpath: &Path,
+        dest_subpath: &Path,
+        dest_fullpath: &Path,
+        src_metadata: &Metadata,
+        owner: Option<(u32, u32)>,
+    ) -> bool {
+        let reference = match &self.options.link_dest {
+            Some(link_dest) => link_dest.join(dest_subpath),
+            None => return false,
+        };
+        if !self
+            .options
+            .matches_reference(src_fullpath, src_metadata, owner, &reference)
+        {
+            return false;
+        }
+        match self
+            .options
+            .file_system
+            .hard_link(&reference, dest_fullpath)
+        {
+            Ok(()) => true,
+            Err(_) => {
+                self.stats.add_ignored_error(ErrorKind::HardLink);
+                false
+            }
+        }
+    }
+
+    /// Finish up after all entries are copied.
+    fn finish(&mut self) -> Result<()> {
+        #[cfg(feature = "tar")]
+        if let Some(builder) = &mut self.tar {
+            return builder
+                .finish()
+                .map_err(|io| Error::from_io_error(io, ErrorKind::WriteFile, PathBuf::new()));
+        }
+        // Set permissions on the deepest directories first.
+        let fs = &*self.options.file_system;
+        for (path, mode) in self.dir_modes.iter().rev() {
+            set_mode(fs, path, *mode)?;
+        }
+        for dir in &self.sync_dirs {
+            sync_dir(fs, dir)?;
+        }
+        if self.options.sync == SyncMode::EndOnly {
+            sync_filesystem(fs, &self.dest)?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for CopyIter<'_> {
+    type Item = CopyEvent;
+
+    fn next(&mut self) -> Option<CopyEvent> {
+        if self.finished {
+            return None;
+        }
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+        let result = self.step();
+        self.update_elapsed();
+        match result {
+            Ok(Some(event)) => Some(event),
+            Ok(None) => {
+                self.finished = true;
+                #[cfg(feature = "tracing")]
+                tracing::info!(
+                    files = self.stats.files,
+                    dirs = self.stats.dirs,
+                    symlinks = self.stats.symlinks,
+                    file_bytes = self.stats.file_bytes,
+                    "copy finished"
+                );
+                None
+            }
+            Err(err) => {
+                self.finished = true;
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    path = %err.path().display(),
+                    kind = ?err.kind(),
+                    error = %err,
+                    "copy failed"
+                );
+                Some(CopyEvent::Error(err))
+            }
+        }
+    }
+}
+
+/// Emit a tracing event describing an entry that was copied or skipped.
+///
+/// Errors are reported when they're returned from the iterator.
+#[cfg(feature = "tracing")]
+fn trace_event(event: &CopyEvent) {
+    match event {
+        CopyEvent::DirCreated { path } => {
+            tracing::debug!(path = %path.display(), "created directory")
+        }
+        CopyEvent::DirMerged { path } => {
+            tracing::debug!(path = %path.display(), "merged directory")
+        }
+        CopyEvent::FileCopied { path, bytes } => {
+            tracing::debug!(path = %path.display(), bytes, "copied file")
+        }
+        CopyEvent::FileLinked { path } => {
+            tracing::debug!(path = %path.display(), "linked file")
+        }
+        CopyEvent::SymlinkCreated { path } => {
+            tracing::debug!(path = %path.display(), "created symlink")
+        }
+        CopyEvent::FilteredOut { path } => {
+            tracing::debug!(path = %path.display(), "filtered out")
+        }
+        CopyEvent::SkippedByLimit { path, limit } => {
+            tracing::debug!(path = %path.display(), ?limit, "skipped by limit")
+        }
+        CopyEvent::OtherFileSystem { path } => {
+            tracing::debug!(path = %path.display(), "skipped directory on other filesystem")
+        }
+        CopyEvent::Error(_) => (),
+    }
+}
+
+impl std::iter::FusedIterator for CopyIter<'_> {}
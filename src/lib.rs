@@ -7,15 +7,48 @@
 //!
 //! # Features
 //!
-//! * Minimal dependencies: currently just `filetime` to support copying mtimes.
+//! * Minimal dependencies: currently just `filetime` to support copying mtimes,
+//!   and `libc` on Unix.
 //! * Returns [CopyStats] describing how much data and how many files were
 //!   copied.
 //! * Tested on Linux, macOS and Windows.
 //! * Copies mtimes and permissions.
 //! * Takes an optional callback to decide which entries are copied or skipped,
 //!   [CopyOptions::filter].
+//! * Takes an optional callback to rename or move entries, [CopyOptions::map_path].
+//! * Takes an optional callback to transform the content of files, such as to render
+//!   templates, [CopyOptions::transform_files].
 //! * Takes an optional callback to show progress or record which files are copied,
 //!   [CopyOptions::after_entry_copied].
+//! * Optionally copies extended attributes on Linux, [CopyOptions::copy_xattrs].
+//! * Optionally copies POSIX ACLs on Linux, [CopyOptions::copy_acls].
+//! * Optionally preserves or remaps file owners on Unix, [CopyOptions::preserve_owner].
+//! * Optionally sets or masks Unix permissions, [CopyOptions::file_mode] and
+//!   [CopyOptions::dir_mode].
+//! * Optionally copies atimes and symlink timestamps, [CopyOptions::copy_atime] and
+//!   [CopyOptions::copy_symlink_times].
+//! * Optionally syncs copied data to disk, [CopyOptions::sync].
+//! * Copies can be driven as an iterator of events, [CopyOptions::into_iter].
+//! * With the `async` feature, copies trees from Tokio, `CopyOptions::copy_tree_async`.
+//! * Copies through a [FileSystem] trait, which can be implemented to copy within
+//!   other storage, and includes an in-memory [MemoryFileSystem] for testing.
+//! * Error handling can be tested by injecting faults with [FaultyFileSystem].
+//! * Optionally refuses to follow symlinks planted in the tree during the copy, on Unix,
+//!   [CopyOptions::hardened].
+//! * Optionally stays on one filesystem, [CopyOptions::one_file_system].
+//! * Optionally walks the tree in sorted or depth-first order, [CopyOptions::sort_by_name]
+//!   and [CopyOptions::traversal].
+//! * Optionally hard links files that are unchanged from a previous backup, like
+//!   `rsync --link-dest`, [CopyOptions::link_dest].
+//! * Optionally limits the depth, number of files, and size of the copy, such as
+//!   [CopyOptions::max_total_bytes].
+//! * With the `cli` feature, a `cp-r` command-line tool.
+//! * With the `tar` feature, copies trees into and out of tar archives,
+//!   `CopyOptions::copy_tree_to_tar` and `CopyOptions::copy_from_tar`.
+//! * With the `serde` feature, copy stats and error reports can be serialized, for
+//!   example as JSON.
+//! * With the `tracing` feature, the copy is described by `tracing` spans and events.
+//! * With the `zip` feature, copies trees out of zip archives, `CopyOptions::copy_from_zip`.
 //!
 //! # Missing features that could be added
 //!
@@ -35,13 +68,140 @@
 //! // Copy this crate's `src` directory.
 //! let dest = tempfile::tempdir().unwrap();
 //! let stats = CopyOptions::new().copy_tree(Path::new("src"), dest.path()).unwrap();
-//! assert_eq!(stats.files, 2);
+//! assert_eq!(stats.files, std::fs::read_dir("src").unwrap().count());
 //! assert_eq!(stats.dirs, 0, "no children");
 //! assert_eq!(stats.symlinks, 0, "no symlinks");
 //! ```
 //!
 //! # Release history
 //!
+//! ## Unreleased
+//!
+//! * API change: [CopyStats] is now `#[non_exhaustive]`, so that counters can be added
+//!   without breaking callers. It can no longer be constructed with a struct literal
+//!   outside this crate.
+//!
+//! * The MSRV is now 1.71, as needed by current Tokio and serde_json, which are used in
+//!   the tests and by the `async` feature.
+//!
+//! * New: [CopyOptions::copy_xattrs] copies extended attributes on Linux, with
+//!   [CopyOptions::xattr_namespaces] to choose which namespaces are copied and
+//!   [CopyOptions::xattr_errors] to control whether failures are fatal.
+//!
+//! * New: [ErrorKind::Xattr] and [ErrorPolicy].
+//!
+//! * New: [CopyOptions::copy_acls] copies POSIX access and default ACLs of files
+//!   and directories on Linux. New [ErrorKind::Acl].
+//!
+//! * New: [CopyOptions::preserve_owner] sets the owner and group of copied
+//!   entries on Unix, optionally remapping ids through [CopyOptions::map_uid]
+//!   and [CopyOptions::map_gid]. New [ErrorKind::SetOwner].
+//!
+//! * New: [CopyOptions::file_mode] and [CopyOptions::dir_mode] set fixed Unix
+//!   permissions on copied files and directories, or mask the source permissions,
+//!   as described by [ModeRule]. New [ErrorKind::SetPermissions].
+//!
+//! * New: [CopyOptions::copy_atime] copies access times as well as modification times.
+//!
+//! * New: [CopyOptions::copy_symlink_times] sets the timestamps of copied symlinks.
+//!
+//! * New: [CopyOptions::time_errors] can report failures to set timestamps, which
+//!   are still ignored by default, as [ErrorKind::SetTimes] errors.
+//!
+//! * New: [CopyOptions::sync] makes sure the copy is durable on disk before
+//!   [CopyOptions::copy_tree] returns, as selected by [SyncMode]. New [ErrorKind::Sync].
+//!
+//! * New: An `async` feature providing `CopyOptions::copy_tree_async`, which copies
+//!   on Tokio's blocking thread pool and can be cancelled by dropping the future. The
+//!   options are built on that thread, so their callbacks need not be `Send`.
+//!
+//! * New: [CopyOptions::into_iter] returns a [CopyIter] that copies the tree one
+//!   entry at a time, yielding [CopyEvent]s, as an alternative to callbacks.
+//!
+//! * New: All filesystem access goes through the [FileSystem] trait, set by
+//!   [CopyOptions::file_system]. [StdFileSystem], the default, uses the real
+//!   filesystem, and [MemoryFileSystem] holds a tree in memory for tests. Files are
+//!   written through [WriteFile], which can sync them before they're closed.
+//!
+//! * API change: The [CopyOptions::filter] callback is passed a cp_r [Entry] rather than
+//!   a [std::fs::DirEntry], and [CopyOptions::after_entry_copied] is passed a cp_r
+//!   [FileType] rather than a [std::fs::FileType], so that they work with any
+//!   [FileSystem]. Callbacks written for the old signatures need their argument types
+//!   changed: [Entry::file_type] and [Entry::path] are available as before, and
+//!   [Entry::metadata] returns the cp_r [Metadata], read when it's first asked for.
+//!
+//! * Fixed: Symlinks copied on Windows are counted in [CopyStats::symlinks].
+//!
+//! * New: [FaultyFileSystem] wraps another [FileSystem] and fails chosen operations,
+//!   selected by [Fault] rules, so that error handling can be tested deterministically.
+//!
+//! * New: A `cp-r` command-line tool, built with the `cli` feature, with options to
+//!   include and exclude paths by glob, show progress, and print stats. Its exit code
+//!   reflects the [ErrorKind] of any failure.
+//!
+//! * New: A `tar` feature providing `CopyOptions::copy_tree_to_tar`, which writes the
+//!   tree into a tar archive rather than a directory, with the same filtering, callbacks
+//!   and stats.
+//!
+//! * New: `CopyOptions::copy_from_tar`, with the `tar` feature, and
+//!   `CopyOptions::copy_from_zip`, with a new `zip` feature, copy the entries of an
+//!   archive into a directory. Entries that could be written outside the destination
+//!   are rejected with the new [ErrorKind::UnsafePath]. New [ErrorKind::ReadArchive].
+//!
+//! * New: [CopyOptions::hardened] opens directories relative to their parents without
+//!   following symlinks, so that a symlink planted in the source or destination during
+//!   the copy can't redirect it outside the tree. New [ErrorKind::UnexpectedSymlink],
+//!   and [ErrorKind::ConflictingOptions] if a hardened copy is given a
+//!   [CopyOptions::file_system] or asked to copy extended attributes or ACLs.
+//!
+//! * New: [CopyOptions::one_file_system] skips directories on other filesystems mounted
+//!   inside the source, counted in [CopyStats::other_filesystem_dirs]. New
+//!   [Metadata::dev], [CopyEvent::OtherFileSystem] and [MemoryFileSystem::set_dev].
+//!
+//! * New: Limits on the copy, [CopyOptions::max_depth], [CopyOptions::max_files],
+//!   [CopyOptions::max_total_bytes] and [CopyOptions::max_file_size]. Entries that are too
+//!   deep or too large are skipped, and counted in [CopyStats::too_deep] and
+//!   [CopyStats::too_large], and exceeding a limit on the whole copy fails with the new
+//!   [ErrorKind::LimitExceeded]. New [Limit] and [CopyEvent::SkippedByLimit].
+//!
+//! * New: Copying a directory into itself fails with [ErrorKind::DestinationInsideSource],
+//!   rather than recursing until the disk fills. New [Metadata::ino].
+//!
+//! * New: A source directory that contains itself, such as through a bind mount, fails
+//!   the copy with [ErrorKind::Cycle] rather than copying forever.
+//!
+//! * New: [CopyOptions::sort_by_name] and [CopyOptions::sort_by] copy the entries of each
+//!   directory in a deterministic order, and [CopyOptions::traversal] can choose a
+//!   depth-first rather than breadth-first walk, as described by [Traversal].
+//!
+//! * New: [CopyStats] records the time taken, split into [CopyStats::read_dir_time] and
+//!   [CopyStats::copy_file_time], the largest and number of empty files, and the number of
+//!   ignored errors of each [ErrorKind]. [CopyStats::bytes_per_second] gives the
+//!   throughput. Stats that differ only in their times compare equal.
+//!
+//! * New: A `serde` feature implementing `Serialize` and `Deserialize` for [CopyStats],
+//!   [ErrorKind] and [Limit], and `Serialize` for [Error] through a new `ErrorReport`
+//!   giving its path, kind, IO error kind and message.
+//!
+//! * New: A `tracing` feature that emits a span for the whole copy and for each
+//!   directory, and events for each entry copied, filtered, or skipped, and for
+//!   failures, with paths relative to the tree and sizes as fields.
+//!
+//! * New: [CopyOptions::map_path] sets a callback choosing the destination path of each
+//!   entry, or skipping it. Directories mapped to the same path are merged, reported by
+//!   [CopyEvent::DirMerged], and other collisions fail with
+//!   [ErrorKind::MappedPathCollision]. New [ErrorKind::InvalidMappedPath].
+//!
+//! * New: [CopyOptions::transform_files] writes the content of chosen files through a
+//!   callback, such as to render templates, while still setting permissions and
+//!   timestamps and counting the bytes written. New [ErrorKind::TransformFile].
+//!
+//! * New: [CopyOptions::link_dest] hard links files that are unchanged from a reference
+//!   tree, such as a previous backup, rather than copying them, compared as set by
+//!   [CopyOptions::link_compare] and [LinkCompare]. New [CopyStats::linked_files],
+//!   [CopyEvent::FileLinked], [ErrorKind::HardLink] and [FileSystem::hard_link]. The
+//!   `cp-r` tool has a matching `--link-dest` option.
+//!
 //! ## 0.5.2
 //!
 //! Released 2024-10-07.
@@ -134,17 +294,34 @@
 
 #![warn(missing_docs)]
 
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::fs::{self, DirEntry};
-use std::io;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
+#[cfg(any(feature = "tar", feature = "zip"))]
+mod archive;
+mod faulty;
+mod filesystem;
+#[cfg(feature = "async")]
+mod future;
+mod hardened;
+mod iter;
+mod memory;
 #[cfg(windows)]
 mod windows;
+mod xattr;
 
-#[cfg(windows)]
-use windows::copy_symlink;
+pub use faulty::{Fault, FaultyFileSystem, Operation};
+pub use filesystem::{Entry, FileSystem, FileType, Metadata, ReadDir, StdFileSystem, WriteFile};
+#[cfg(feature = "async")]
+pub use future::CopyTreeFuture;
+pub use iter::{CopyEvent, CopyIter};
+pub use memory::MemoryFileSystem;
 
 /// Options for copying file trees.
 ///
@@ -156,22 +333,90 @@ pub struct CopyOptions<'f> {
     // TODO: Option controlling whether to copy mtimes?
     // TODO: Copy permissions?
     create_destination: bool,
+    copy_xattrs: bool,
+    xattr_namespaces: Vec<String>,
+    xattr_errors: ErrorPolicy,
+    copy_acls: bool,
+    preserve_owner: bool,
+    map_uid: Option<Box<dyn FnMut(u32) -> u32 + 'f>>,
+    map_gid: Option<Box<dyn FnMut(u32) -> u32 + 'f>>,
+    file_mode: ModeRule,
+    dir_mode: Option<ModeRule>,
+    copy_atime: bool,
+    copy_symlink_times: bool,
+    time_errors: ErrorPolicy,
+    sync: SyncMode,
+    file_system: Box<dyn FileSystem + 'f>,
+    /// True if the filesystem was set by [CopyOptions::file_system].
+    custom_file_system: bool,
+    hardened: bool,
+    one_file_system: bool,
+    max_depth: Option<usize>,
+    max_files: Option<usize>,
+    max_total_bytes: Option<u64>,
+    max_file_size: Option<u64>,
+    traversal: Traversal,
+    link_dest: Option<PathBuf>,
+    link_compare: LinkCompare,
+    /// Set to stop the copy before the next entry, when an async copy is cancelled.
+    cancel: Option<Arc<AtomicBool>>,
 
     // I agree with Clippy that the callbacks are complex types, but stable Rust
     // seems to have no other way to spell it, because you can't make a type or
     // trait alias for a Fn.
     #[allow(clippy::type_complexity)]
-    filter: Option<Box<dyn FnMut(&Path, &DirEntry) -> Result<bool> + 'f>>,
+    filter: Option<Box<dyn FnMut(&Path, &Entry) -> Result<bool> + 'f>>,
 
     #[allow(clippy::type_complexity)]
-    after_entry_copied: Option<Box<dyn FnMut(&Path, &fs::FileType, &CopyStats) -> Result<()> + 'f>>,
+    map_path: Option<Box<dyn FnMut(&Path, &Entry) -> Result<Option<PathBuf>> + 'f>>,
+
+    #[allow(clippy::type_complexity)]
+    sort: Option<Box<dyn FnMut(&Entry, &Entry) -> Ordering + 'f>>,
+
+    #[allow(clippy::type_complexity)]
+    transform_predicate: Option<Box<dyn FnMut(&Path, &Entry) -> bool + 'f>>,
+
+    #[allow(clippy::type_complexity)]
+    transform: Option<Box<dyn FnMut(&Path, &mut dyn Read, &mut dyn Write) -> io::Result<()> + 'f>>,
+
+    #[allow(clippy::type_complexity)]
+    after_entry_copied: Option<Box<dyn FnMut(&Path, &FileType, &CopyStats) -> Result<()> + 'f>>,
 }
 
 impl<'f> Default for CopyOptions<'f> {
     fn default() -> CopyOptions<'f> {
         CopyOptions {
             create_destination: true,
+            copy_xattrs: false,
+            xattr_namespaces: vec!["user".to_owned()],
+            xattr_errors: ErrorPolicy::Fail,
+            copy_acls: false,
+            preserve_owner: false,
+            map_uid: None,
+            map_gid: None,
+            file_mode: ModeRule::Preserve,
+            dir_mode: None,
+            copy_atime: false,
+            copy_symlink_times: false,
+            time_errors: ErrorPolicy::Ignore,
+            sync: SyncMode::None,
+            file_system: Box::new(StdFileSystem),
+            custom_file_system: false,
+            hardened: false,
+            one_file_system: false,
+            max_depth: None,
+            max_files: None,
+            max_total_bytes: None,
+            max_file_size: None,
+            traversal: Traversal::BreadthFirst,
+            link_dest: None,
+            link_compare: LinkCompare::SizeAndMtime,
+            cancel: None,
             filter: None,
+            map_path: None,
+            sort: None,
+            transform_predicate: None,
+            transform: None,
             after_entry_copied: None,
         }
     }
@@ -189,7 +434,491 @@ impl<'f> CopyOptions<'f> {
     #[must_use]
     pub fn create_destination(self, create_destination: bool) -> CopyOptions<'f> {
         CopyOptions {
-            create_destination,
+            create_destination,
+            ..self
+        }
+    }
+
+    /// Set whether to copy extended attributes of files, directories and symlinks.
+    ///
+    /// By default extended attributes are not copied. Extended attributes are
+    /// currently only supported on Linux: on other platforms turning this on
+    /// will cause an [ErrorKind::Xattr] error, unless errors are ignored by
+    /// [CopyOptions::xattr_errors].
+    ///
+    /// Only attributes in the namespaces set by [CopyOptions::xattr_namespaces]
+    /// are copied, by default just `user`.
+    #[must_use]
+    pub fn copy_xattrs(self, copy_xattrs: bool) -> CopyOptions<'f> {
+        CopyOptions {
+            copy_xattrs,
+            ..self
+        }
+    }
+
+    /// Set which extended attribute namespaces are copied, such as `user`, `trusted`,
+    /// or `security`.
+    ///
+    /// An attribute is copied if the part of its name before the first dot is
+    /// one of these namespaces. The default is just `user`. Copying attributes
+    /// in other namespaces typically requires elevated privileges.
+    ///
+    /// This has no effect unless [CopyOptions::copy_xattrs] is turned on.
+    #[must_use]
+    pub fn xattr_namespaces<I, S>(self, namespaces: I) -> CopyOptions<'f>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        CopyOptions {
+            xattr_namespaces: namespaces.into_iter().map(Into::into).collect(),
+            ..self
+        }
+    }
+
+    /// Set whether failures to read or write extended attributes stop the copy (the default)
+    /// or are ignored.
+    ///
+    /// Ignoring errors may be useful when copying to a filesystem that doesn't support
+    /// extended attributes, or doesn't support them on symlinks.
+    #[must_use]
+    pub fn xattr_errors(self, xattr_errors: ErrorPolicy) -> CopyOptions<'f> {
+        CopyOptions {
+            xattr_errors,
+            ..self
+        }
+    }
+
+    /// Set whether to copy POSIX ACLs of files and directories.
+    ///
+    /// Both the access ACL and, on directories, the default ACL that is inherited
+    /// by new files are copied. By default ACLs are not copied, and the destination
+    /// gets only the basic permission bits.
+    ///
+    /// ACLs are currently only supported on Linux, where they are stored in
+    /// `system.posix_acl_*` extended attributes. Failures to read or set ACLs
+    /// cause an [ErrorKind::Acl] error.
+    #[must_use]
+    pub fn copy_acls(self, copy_acls: bool) -> CopyOptions<'f> {
+        CopyOptions { copy_acls, ..self }
+    }
+
+    /// Set whether to give copied files, directories and symlinks the same owner and group
+    /// as the source.
+    ///
+    /// By default the owner is not preserved, and new entries are owned by the
+    /// user running the copy, as usual. Changing the owner to another user
+    /// typically requires running as root.
+    ///
+    /// The ids can be changed on the way through [CopyOptions::map_uid] and
+    /// [CopyOptions::map_gid].
+    ///
+    /// Owners are only supported on Unix: on other platforms turning this on causes
+    /// an [ErrorKind::SetOwner] error.
+    #[must_use]
+    pub fn preserve_owner(self, preserve_owner: bool) -> CopyOptions<'f> {
+        CopyOptions {
+            preserve_owner,
+            ..self
+        }
+    }
+
+    /// Set a callback that maps the user id of the source to the user id set on the
+    /// destination.
+    ///
+    /// For example, to shift all ids into the range used by a user namespace:
+    ///
+    /// ```
+    /// let options = cp_r::CopyOptions::new()
+    ///     .preserve_owner(true)
+    ///     .map_uid(|uid| uid + 100000)
+    ///     .map_gid(|gid| gid + 100000);
+    /// ```
+    ///
+    /// A table of ids can be applied by looking them up in a map inside the callback.
+    ///
+    /// This has no effect unless [CopyOptions::preserve_owner] is turned on.
+    #[must_use]
+    pub fn map_uid<F>(self, map_uid: F) -> CopyOptions<'f>
+    where
+        F: FnMut(u32) -> u32 + 'f,
+    {
+        CopyOptions {
+            map_uid: Some(Box::new(map_uid)),
+            ..self
+        }
+    }
+
+    /// Set a callback that maps the group id of the source to the group id set on the
+    /// destination.
+    ///
+    /// This has no effect unless [CopyOptions::preserve_owner] is turned on.
+    #[must_use]
+    pub fn map_gid<F>(self, map_gid: F) -> CopyOptions<'f>
+    where
+        F: FnMut(u32) -> u32 + 'f,
+    {
+        CopyOptions {
+            map_gid: Some(Box::new(map_gid)),
+            ..self
+        }
+    }
+
+    /// Set how the Unix permissions of copied files are determined.
+    ///
+    /// By default, files have the same permissions as their source.
+    ///
+    /// ```
+    /// use cp_r::{CopyOptions, ModeRule};
+    ///
+    /// // Make deployed files world-readable, and never setuid or setgid.
+    /// let options = CopyOptions::new()
+    ///     .file_mode(ModeRule::Mask { and: !0o6000, or: 0o444 })
+    ///     .dir_mode(ModeRule::Mask { and: !0o6000, or: 0o555 });
+    /// ```
+    ///
+    /// Modes other than [ModeRule::Preserve] are only supported on Unix: on other platforms
+    /// they cause an [ErrorKind::SetPermissions] error.
+    #[must_use]
+    pub fn file_mode(self, file_mode: ModeRule) -> CopyOptions<'f> {
+        CopyOptions { file_mode, ..self }
+    }
+
+    /// Set how the Unix permissions of copied directories are determined.
+    ///
+    /// By default, directories are created with the default permissions for new
+    /// directories, as determined by the process umask.
+    ///
+    /// Permissions are set on directories after all their contents have been
+    /// copied, so that copying into directories that will be made read-only succeeds.
+    ///
+    /// This is only supported on Unix: on other platforms it causes an
+    /// [ErrorKind::SetPermissions] error.
+    #[must_use]
+    pub fn dir_mode(self, dir_mode: ModeRule) -> CopyOptions<'f> {
+        CopyOptions {
+            dir_mode: Some(dir_mode),
+            ..self
+        }
+    }
+
+    /// Set whether to copy the access time of files, as well as the modification time.
+    ///
+    /// By default only the modification time is copied, and the access time is left
+    /// as the time the file was copied. Timestamps are copied with the full precision
+    /// supported by the platform and filesystems.
+    #[must_use]
+    pub fn copy_atime(self, copy_atime: bool) -> CopyOptions<'f> {
+        CopyOptions { copy_atime, ..self }
+    }
+
+    /// Set whether to copy the timestamps of symlinks themselves.
+    ///
+    /// By default symlinks get the time at which they were created. Not all platforms
+    /// support setting timestamps on symlinks.
+    #[must_use]
+    pub fn copy_symlink_times(self, copy_symlink_times: bool) -> CopyOptions<'f> {
+        CopyOptions {
+            copy_symlink_times,
+            ..self
+        }
+    }
+
+    /// Set whether failures to set timestamps are ignored (the default) or stop the copy.
+    ///
+    /// For example, setting the mtime fails on Windows if the file is read-only.
+    #[must_use]
+    pub fn time_errors(self, time_errors: ErrorPolicy) -> CopyOptions<'f> {
+        CopyOptions {
+            time_errors,
+            ..self
+        }
+    }
+
+    /// Set whether and how to sync the copied data to disk, so that the copy
+    /// survives a crash or power loss.
+    ///
+    /// By default nothing is synced, and the operating system writes the data in its own
+    /// time.
+    ///
+    /// ```
+    /// use cp_r::{CopyOptions, SyncMode};
+    ///
+    /// let dest = tempfile::tempdir().unwrap();
+    /// let stats = CopyOptions::new()
+    ///     .sync(SyncMode::FilesAndDirs)
+    ///     .copy_tree("src", &dest)
+    ///     .unwrap();
+    /// ```
+    #[must_use]
+    pub fn sync(self, sync: SyncMode) -> CopyOptions<'f> {
+        CopyOptions { sync, ..self }
+    }
+
+    /// Set the filesystem that the tree is copied from and to.
+    ///
+    /// By default this is [StdFileSystem], the real filesystem. Other implementations
+    /// of [FileSystem], such as [MemoryFileSystem], can be used to copy trees in
+    /// other storage, or to test code that copies trees.
+    #[must_use]
+    pub fn file_system<F>(self, file_system: F) -> CopyOptions<'f>
+    where
+        F: FileSystem + 'f,
+    {
+        CopyOptions {
+            file_system: Box::new(file_system),
+            custom_file_system: true,
+            ..self
+        }
+    }
+
+    /// Set whether to refuse to follow symlinks that appear inside the source or
+    /// destination while they're being copied.
+    ///
+    /// If another process can change the source or destination during the copy, it
+    /// could replace a directory by a symlink, such as `dest/a -> /etc`, so that files
+    /// are read from or written to somewhere outside the tree. In hardened mode, every
+    /// directory beneath the source and destination is opened relative to its parent
+    /// with `openat` and `O_NOFOLLOW`, and finding a symlink where a directory or file
+    /// was expected fails the copy with [ErrorKind::UnexpectedSymlink]. Symlinks in the
+    /// source are still copied as symlinks.
+    ///
+    /// The source and destination paths themselves are trusted, and may be or contain
+    /// symlinks.
+    ///
+    /// Hardened copies always use the real filesystem, and are only supported on Unix.
+    /// Extended attributes and ACLs can't yet be copied in hardened mode. A hardened copy
+    /// with a filesystem set by [CopyOptions::file_system], or with
+    /// [CopyOptions::copy_xattrs] or [CopyOptions::copy_acls], fails with
+    /// [ErrorKind::ConflictingOptions] before anything is copied.
+    ///
+    /// ```
+    /// # #[cfg(unix)] {
+    /// use cp_r::CopyOptions;
+    ///
+    /// let dest = tempfile::tempdir().unwrap();
+    /// let stats = CopyOptions::new()
+    ///     .hardened(true)
+    ///     .copy_tree("src", &dest)
+    ///     .unwrap();
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn hardened(self, hardened: bool) -> CopyOptions<'f> {
+        CopyOptions { hardened, ..self }
+    }
+
+    /// Set whether to skip directories on a different filesystem from the source, like
+    /// `cp -x`.
+    ///
+    /// This avoids copying other filesystems mounted inside the source, such as network
+    /// shares, or `/proc` when copying `/`. Directories whose [Metadata::dev] differs from
+    /// that of the source directory are skipped along with their contents, and counted in
+    /// [CopyStats::other_filesystem_dirs], unless they were already filtered out by
+    /// [CopyOptions::filter].
+    ///
+    /// By default, the copy crosses into other filesystems. Device ids are only known
+    /// on Unix, so this has no effect on other platforms.
+    #[must_use]
+    pub fn one_file_system(self, one_file_system: bool) -> CopyOptions<'f> {
+        CopyOptions {
+            one_file_system,
+            ..self
+        }
+    }
+
+    /// Set the maximum depth of entries to copy, below the top of the tree.
+    ///
+    /// Entries directly inside the source directory are at depth 1, so with a maximum
+    /// depth of 1 the subdirectories are created, but empty. Entries that are too deep are
+    /// skipped and counted in [CopyStats::too_deep]; the contents of skipped directories
+    /// are not read.
+    ///
+    /// By default there is no limit.
+    #[must_use]
+    pub fn max_depth(self, max_depth: usize) -> CopyOptions<'f> {
+        CopyOptions {
+            max_depth: Some(max_depth),
+            ..self
+        }
+    }
+
+    /// Set the maximum number of files to copy.
+    ///
+    /// If the tree has more files, the copy fails with
+    /// [ErrorKind::LimitExceeded]\([Limit::MaxFiles]) before copying the first file over
+    /// the limit. Directories and symlinks are not counted, nor are files hard linked by
+    /// [CopyOptions::link_dest].
+    ///
+    /// By default there is no limit.
+    #[must_use]
+    pub fn max_files(self, max_files: usize) -> CopyOptions<'f> {
+        CopyOptions {
+            max_files: Some(max_files),
+            ..self
+        }
+    }
+
+    /// Set the maximum number of bytes of file content to copy, across all files.
+    ///
+    /// If copying a file would take the total over the limit, the copy fails with
+    /// [ErrorKind::LimitExceeded]\([Limit::MaxTotalBytes]) before copying that file. The
+    /// size of each file is taken from when its directory was listed.
+    ///
+    /// Files written through [CopyOptions::transform_files] are checked by the size of
+    /// their source, since the output size isn't known until they're written, but once
+    /// written they count toward the total by the number of bytes actually written, as in
+    /// [CopyStats::file_bytes].
+    ///
+    /// Files hard linked by [CopyOptions::link_dest] take no new space, so they don't
+    /// count toward the total.
+    ///
+    /// By default there is no limit.
+    #[must_use]
+    pub fn max_total_bytes(self, max_total_bytes: u64) -> CopyOptions<'f> {
+        CopyOptions {
+            max_total_bytes: Some(max_total_bytes),
+            ..self
+        }
+    }
+
+    /// Set the maximum size of a file to copy.
+    ///
+    /// Larger files are skipped and counted in [CopyStats::too_large].
+    ///
+    /// By default there is no limit.
+    #[must_use]
+    pub fn max_file_size(self, max_file_size: u64) -> CopyOptions<'f> {
+        CopyOptions {
+            max_file_size: Some(max_file_size),
+            ..self
+        }
+    }
+
+    /// Hard link files to identical files in a reference tree, such as a previous backup,
+    /// rather than copying their content, like `rsync --link-dest`.
+    ///
+    /// A file is linked to the file at the same path, relative to the top of the
+    /// destination, under `reference`, if that is a plain file that matches the source
+    /// according to [CopyOptions::link_compare], and has the permissions the copy would
+    /// have, and the same owner if [CopyOptions::preserve_owner] is set. Other files are
+    /// copied as usual. Extended attributes and ACLs are not compared.
+    ///
+    /// Linked files share their content and metadata with the reference, so neither is
+    /// changed. They're counted in [CopyStats::linked_files] rather than
+    /// [CopyStats::files], and reported by [CopyEvent::FileLinked]. If a link can't be
+    /// made, for example because the reference is on a different filesystem, the file is
+    /// copied instead, and the error counted in [CopyStats::ignored_errors] as
+    /// [ErrorKind::HardLink].
+    ///
+    /// Files written by [CopyOptions::transform_files] are never linked. Linking is not
+    /// done when copying into or out of archives.
+    ///
+    /// Linked files are still skipped if they're larger than [CopyOptions::max_file_size],
+    /// but they aren't counted toward [CopyOptions::max_files] or
+    /// [CopyOptions::max_total_bytes].
+    ///
+    /// ```
+    /// use std::fs;
+    /// use cp_r::CopyOptions;
+    ///
+    /// let src = tempfile::tempdir().unwrap();
+    /// fs::write(src.path().join("unchanged"), b"hello").unwrap();
+    /// let backups = tempfile::tempdir().unwrap();
+    /// let monday = backups.path().join("monday");
+    /// let tuesday = backups.path().join("tuesday");
+    /// CopyOptions::new().copy_tree(&src, &monday).unwrap();
+    ///
+    /// let stats = CopyOptions::new()
+    ///     .link_dest(&monday)
+    ///     .copy_tree(&src, &tuesday)
+    ///     .unwrap();
+    /// # #[cfg(unix)]
+    /// assert_eq!(stats.linked_files, 1);
+    /// assert_eq!(fs::read(tuesday.join("unchanged")).unwrap(), b"hello");
+    /// ```
+    #[must_use]
+    pub fn link_dest<P: AsRef<Path>>(self, reference: P) -> CopyOptions<'f> {
+        CopyOptions {
+            link_dest: Some(reference.as_ref().to_owned()),
+            ..self
+        }
+    }
+
+    /// Set how files are compared to the reference tree set by [CopyOptions::link_dest]
+    /// to decide whether they can be linked.
+    ///
+    /// By default files match if they have the same size and mtime, like `rsync`.
+    #[must_use]
+    pub fn link_compare(self, link_compare: LinkCompare) -> CopyOptions<'f> {
+        CopyOptions {
+            link_compare,
+            ..self
+        }
+    }
+
+    /// Set whether to copy all the entries at one depth before going deeper (the
+    /// default), or to copy each directory's contents as soon as it's created.
+    ///
+    /// A depth-first copy keeps one directory open for each level of the tree that it's
+    /// inside, unless the entries are sorted.
+    ///
+    /// ```
+    /// use cp_r::{CopyOptions, Traversal};
+    ///
+    /// let dest = tempfile::tempdir().unwrap();
+    /// let stats = CopyOptions::new()
+    ///     .traversal(Traversal::DepthFirst)
+    ///     .copy_tree("src", &dest)
+    ///     .unwrap();
+    /// ```
+    #[must_use]
+    pub fn traversal(self, traversal: Traversal) -> CopyOptions<'f> {
+        CopyOptions { traversal, ..self }
+    }
+
+    /// Copy the entries of each directory in order of their names, so that events,
+    /// callbacks, and errors are the same on every machine.
+    ///
+    /// By default entries are copied in the order the filesystem lists them, which
+    /// varies between filesystems. Sorting reads the whole directory before copying
+    /// anything from it.
+    ///
+    /// Names are compared as [std::ffi::OsStr]s, which on Unix compares their bytes.
+    ///
+    /// ```
+    /// use std::path::Path;
+    /// use cp_r::{CopyEvent, CopyOptions};
+    ///
+    /// let dest = tempfile::tempdir().unwrap();
+    /// let files: Vec<_> = CopyOptions::new()
+    ///     .sort_by_name()
+    ///     .into_iter("src", &dest)
+    ///     .filter_map(|event| match event {
+    ///         CopyEvent::FileCopied { path, .. } => Some(path),
+    ///         _ => None,
+    ///     })
+    ///     .collect();
+    /// let mut sorted = files.clone();
+    /// sorted.sort();
+    /// assert_eq!(files, sorted);
+    /// ```
+    #[must_use]
+    pub fn sort_by_name(self) -> CopyOptions<'f> {
+        self.sort_by(|a, b| a.file_name().cmp(b.file_name()))
+    }
+
+    /// Copy the entries of each directory in the order given by a comparison function.
+    ///
+    /// Like [CopyOptions::sort_by_name], this reads the whole of each directory before
+    /// copying anything from it.
+    #[must_use]
+    pub fn sort_by<F>(self, compare: F) -> CopyOptions<'f>
+    where
+        F: FnMut(&Entry, &Entry) -> Ordering + 'f,
+    {
+        CopyOptions {
+            sort: Some(Box::new(compare)),
             ..self
         }
     }
@@ -201,7 +930,7 @@ impl<'f> CopyOptions<'f> {
     /// * `Ok(false)` to skip an entry (and anything inside the directory)
     /// * `Err(_)` to stop copying and return this error
     ///
-    /// The path is relative to the top of the tree. The [std::fs::DirEntry] gives access to the file type and other metadata of the source file.
+    /// The path is relative to the top of the tree. The [Entry] gives access to the file type and other metadata of the source file.
     ///
     /// ```
     /// use std::fs;
@@ -231,7 +960,7 @@ impl<'f> CopyOptions<'f> {
     #[must_use]
     pub fn filter<F>(self, filter: F) -> CopyOptions<'f>
     where
-        F: FnMut(&Path, &DirEntry) -> Result<bool> + 'f,
+        F: FnMut(&Path, &Entry) -> Result<bool> + 'f,
     {
         CopyOptions {
             filter: Some(Box::new(filter)),
@@ -239,11 +968,113 @@ impl<'f> CopyOptions<'f> {
         }
     }
 
+    /// Set a callback that chooses where each entry is copied to, for example to flatten
+    /// directories, change extensions, or lowercase names.
+    ///
+    /// The callback is passed the path of the entry relative to the top of the source, and
+    /// the [Entry], after the [CopyOptions::filter] and any limits are applied. It can
+    /// return
+    /// * `Ok(Some(path))` to copy the entry to this path, relative to the top of the
+    ///   destination
+    /// * `Ok(None)` to skip the entry (and anything inside the directory), which is
+    ///   counted in [CopyStats::filtered_out]
+    /// * `Err(_)` to stop copying and return this error
+    ///
+    /// The contents of a directory are still passed to the callback with their source
+    /// paths, and they're not moved along with the directory unless the callback maps
+    /// them into it. The parent of each destination must already have been copied, or be
+    /// the top of the destination.
+    ///
+    /// Several directories can be mapped to the same path, including the empty path for
+    /// the top of the destination, and their contents are merged, as reported by
+    /// [CopyEvent::DirMerged]. Mapping any other entry to a path that's already been used
+    /// fails with [ErrorKind::MappedPathCollision], and returning an absolute path or one
+    /// containing `..` fails with [ErrorKind::InvalidMappedPath].
+    ///
+    /// Events, stats and other callbacks still describe entries by their source path.
+    ///
+    /// ```
+    /// use std::fs;
+    /// use std::path::Path;
+    /// use cp_r::CopyOptions;
+    ///
+    /// let src = tempfile::tempdir().unwrap();
+    /// fs::create_dir(src.path().join("Sub")).unwrap();
+    /// fs::write(src.path().join("Sub/A.TXT"), b"hello").unwrap();
+    /// let dest = tempfile::tempdir().unwrap();
+    ///
+    /// CopyOptions::new()
+    ///     .map_path(|path, _entry| Ok(Some(path.to_str().unwrap().to_lowercase().into())))
+    ///     .copy_tree(&src.path(), &dest.path())
+    ///     .unwrap();
+    ///
+    /// assert!(dest.path().join("sub/a.txt").is_file());
+    /// ```
+    #[must_use]
+    pub fn map_path<F>(self, map_path: F) -> CopyOptions<'f>
+    where
+        F: FnMut(&Path, &Entry) -> Result<Option<PathBuf>> + 'f,
+    {
+        CopyOptions {
+            map_path: Some(Box::new(map_path)),
+            ..self
+        }
+    }
+
+    /// Set a callback that writes the content of some files, rather than copying it, for
+    /// example to render templates.
+    ///
+    /// For each file for which `predicate` returns true, `transform` is called with the
+    /// path relative to the top of the tree, a reader of the source file, and a writer of
+    /// the destination file. Other files are copied as usual.
+    ///
+    /// The destination still gets permissions and timestamps according to the options,
+    /// and the number of bytes written by the callback is counted in
+    /// [CopyStats::file_bytes]. If the callback returns an error, it stops the copy
+    /// with an [ErrorKind::TransformFile] error.
+    ///
+    /// ```
+    /// use std::fs;
+    /// use std::io::{Read, Write};
+    /// use cp_r::CopyOptions;
+    ///
+    /// let src = tempfile::tempdir().unwrap();
+    /// fs::write(src.path().join("app.conf"), b"port = {{PORT}}\n").unwrap();
+    /// let dest = tempfile::tempdir().unwrap();
+    ///
+    /// let stats = CopyOptions::new()
+    ///     .transform_files(
+    ///         |path, _entry| path.extension().map_or(false, |ext| ext == "conf"),
+    ///         |_path, reader, writer| {
+    ///             let mut template = String::new();
+    ///             reader.read_to_string(&mut template)?;
+    ///             writer.write_all(template.replace("{{PORT}}", "8080").as_bytes())
+    ///         },
+    ///     )
+    ///     .copy_tree(&src.path(), &dest.path())
+    ///     .unwrap();
+    ///
+    /// assert_eq!(fs::read(dest.path().join("app.conf")).unwrap(), b"port = 8080\n");
+    /// assert_eq!(stats.file_bytes, 12);
+    /// ```
+    #[must_use]
+    pub fn transform_files<P, F>(self, predicate: P, transform: F) -> CopyOptions<'f>
+    where
+        P: FnMut(&Path, &Entry) -> bool + 'f,
+        F: FnMut(&Path, &mut dyn Read, &mut dyn Write) -> io::Result<()> + 'f,
+    {
+        CopyOptions {
+            transform_predicate: Some(Box::new(predicate)),
+            transform: Some(Box::new(transform)),
+            ..self
+        }
+    }
+
     /// Set a progress callback that's called after each entry is successfully copied.
     ///
     /// The callback is passed:
     /// * The path, relative to the top of the tree, that was just copied.
-    /// * The [std::fs::FileType] of the entry that was copied.
+    /// * The [FileType] of the entry that was copied.
     /// * The [stats](CopyStats) so far, including the number of files copied.
     ///
     /// If the callback returns an error, it will abort the copy and the same
@@ -251,7 +1082,7 @@ impl<'f> CopyOptions<'f> {
     #[must_use]
     pub fn after_entry_copied<F>(self, after_entry_copied: F) -> CopyOptions<'f>
     where
-        F: FnMut(&Path, &fs::FileType, &CopyStats) -> Result<()> + 'f,
+        F: FnMut(&Path, &FileType, &CopyStats) -> Result<()> + 'f,
     {
         CopyOptions {
             after_entry_copied: Some(Box::new(after_entry_copied)),
@@ -262,70 +1093,516 @@ impl<'f> CopyOptions<'f> {
     /// Copy the tree according to the options.
     ///
     /// Returns [CopyStats] describing how many files were copied, etc.
-    pub fn copy_tree<P, Q>(mut self, src: P, dest: Q) -> Result<CopyStats>
+    pub fn copy_tree<P, Q>(self, src: P, dest: Q) -> Result<CopyStats>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let mut iter = self.into_iter(src, dest);
+        for event in &mut iter {
+            if let CopyEvent::Error(err) = event {
+                return Err(err);
+            }
+        }
+        Ok(iter.into_stats())
+    }
+
+    /// Return an iterator that copies the tree one entry at a time, returning a
+    /// [CopyEvent] for each entry.
+    ///
+    /// This lets the caller show progress, stop early, or collect results with ordinary
+    /// iterator code, rather than through callbacks.
+    ///
+    /// ```
+    /// use std::path::Path;
+    /// use cp_r::{CopyEvent, CopyOptions};
+    ///
+    /// let dest = tempfile::tempdir().unwrap();
+    /// let mut files_copied = Vec::new();
+    /// for event in CopyOptions::new().into_iter("src", &dest) {
+    ///     match event {
+    ///         CopyEvent::FileCopied { path, bytes } => files_copied.push(path),
+    ///         CopyEvent::Error(err) => panic!("copy failed: {}", err),
+    ///         _ => (),
+    ///     }
+    /// }
+    /// assert!(files_copied.contains(&Path::new("lib.rs").to_owned()));
+    /// ```
+    pub fn into_iter<P, Q>(self, src: P, dest: Q) -> CopyIter<'f>
     where
         P: AsRef<Path>,
         Q: AsRef<Path>,
     {
-        let src = src.as_ref();
-        let dest = dest.as_ref();
+        CopyIter::new(self, src.as_ref().to_owned(), dest.as_ref().to_owned())
+    }
 
-        let mut stats = CopyStats::default();
+    /// If the copy is hardened, switch to a filesystem that doesn't follow symlinks
+    /// beneath the given roots.
+    ///
+    /// Other errors are reported as `kind` on the first root.
+    fn harden(&mut self, roots: &[&Path], kind: ErrorKind) -> Result<()> {
+        if !self.hardened {
+            return Ok(());
+        }
+        if self.custom_file_system || self.copy_xattrs || self.copy_acls {
+            return Err(Error::new(ErrorKind::ConflictingOptions, roots[0]));
+        }
+        #[cfg(unix)]
+        {
+            let _ = kind;
+            self.file_system = Box::new(hardened::HardenedFileSystem::new(roots));
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        {
+            Err(Error::from_io_error(
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "hardened copies are only supported on Unix",
+                ),
+                kind,
+                roots[0],
+            ))
+        }
+    }
 
-        // TODO: Handle the src not being a dir: copy that single entry.
-        if self.create_destination {
-            if !dest.is_dir() {
-                copy_dir(src, dest, &mut stats)?;
+    /// Check an entry, at a path relative to the top of the tree, against the limits that
+    /// skip single entries.
+    ///
+    /// Returns the limit that the entry is skipped by, if any, after counting it in the
+    /// stats.
+    fn check_limits(
+        &self,
+        path: &Path,
+        entry: &Entry,
+        stats: &mut CopyStats,
+    ) -> Result<Option<Limit>> {
+        if let Some(max_depth) = self.max_depth {
+            if path.components().count() > max_depth {
+                stats.too_deep += 1;
+                return Ok(Some(Limit::MaxDepth));
             }
-        } else if !dest.is_dir() {
-            return Err(Error::new(ErrorKind::DestinationDoesNotExist, dest));
-        }
-
-        let mut subdir_queue: VecDeque<PathBuf> = VecDeque::new();
-        subdir_queue.push_back(PathBuf::from(""));
-
-        while let Some(subdir) = subdir_queue.pop_front() {
-            let subdir_full_path = src.join(&subdir);
-            for entry in fs::read_dir(&subdir_full_path)
-                .map_err(|io| Error::from_io_error(io, ErrorKind::ReadDir, &subdir_full_path))?
-            {
-                let dir_entry = entry.map_err(|io| {
-                    Error::from_io_error(io, ErrorKind::ReadDir, &subdir_full_path)
-                })?;
-                let entry_subpath = subdir.join(dir_entry.file_name());
-                if let Some(filter) = &mut self.filter {
-                    if !filter(&entry_subpath, &dir_entry)? {
-                        stats.filtered_out += 1;
-                        continue;
-                    }
+        }
+        if entry.file_type().is_file() {
+            let metadata = entry_metadata(entry)?;
+            if let Some(max_file_size) = self.max_file_size {
+                if metadata.len > max_file_size {
+                    stats.too_large += 1;
+                    return Ok(Some(Limit::MaxFileSize));
                 }
-                let src_fullpath = src.join(&entry_subpath);
-                let dest_fullpath = dest.join(&entry_subpath);
-                let file_type = dir_entry
-                    .file_type()
-                    .map_err(|io| Error::from_io_error(io, ErrorKind::ReadDir, &src_fullpath))?;
-                if file_type.is_file() {
-                    copy_file(&src_fullpath, &dest_fullpath, &mut stats)?
-                } else if file_type.is_dir() {
-                    copy_dir(&src_fullpath, &dest_fullpath, &mut stats)?;
-                    subdir_queue.push_back(entry_subpath.clone());
-                } else if file_type.is_symlink() {
-                    copy_symlink(&src_fullpath, &dest_fullpath, &mut stats)?
-                } else {
-                    // TODO: Include the file type.
-                    return Err(Error::new(ErrorKind::UnsupportedFileType, src_fullpath));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check that copying an entry wouldn't exceed a limit on the whole copy, returning
+    /// an error on the entry's own path if it would.
+    ///
+    /// This is checked just before a file's content is copied, so that files hard linked
+    /// to the reference tree aren't counted.
+    fn check_total_limits(&self, entry: &Entry, stats: &CopyStats) -> Result<()> {
+        if entry.file_type().is_file() {
+            let metadata = entry_metadata(entry)?;
+            if let Some(max_files) = self.max_files {
+                if stats.files >= max_files {
+                    return Err(Error::new(
+                        ErrorKind::LimitExceeded(Limit::MaxFiles),
+                        entry.path(),
+                    ));
                 }
-                if let Some(ref mut f) = self.after_entry_copied {
-                    f(&entry_subpath, &file_type, &stats)?;
+            }
+            if let Some(max_total_bytes) = self.max_total_bytes {
+                if stats.file_bytes.saturating_add(metadata.len) > max_total_bytes {
+                    return Err(Error::new(
+                        ErrorKind::LimitExceeded(Limit::MaxTotalBytes),
+                        entry.path(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the path, relative to the top of the destination, that an entry is copied to,
+    /// or None if it's skipped by the [CopyOptions::map_path] callback.
+    ///
+    /// If the callback is set, the destination is recorded in `dest_paths`, and this
+    /// returns an error on the entry's own path if it collides with an earlier entry. The flag is true for a
+    /// directory mapped onto one that already exists.
+    fn map_dest_path(
+        &mut self,
+        path: &Path,
+        entry: &Entry,
+        kind: Kind,
+        dest_paths: &mut HashMap<PathBuf, Kind>,
+    ) -> Result<Option<(PathBuf, bool)>> {
+        let map_path = match &mut self.map_path {
+            Some(map_path) => map_path,
+            None => return Ok(Some((path.to_owned(), false))),
+        };
+        let mapped = match map_path(path, entry)? {
+            Some(mapped) => mapped,
+            None => return Ok(None),
+        };
+        let mut dest_path = PathBuf::new();
+        for component in mapped.components() {
+            match component {
+                Component::Normal(name) => dest_path.push(name),
+                Component::CurDir => (),
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(Error::new(ErrorKind::InvalidMappedPath, entry.path()));
                 }
             }
         }
-        Ok(stats)
+        // The top of the destination always exists.
+        dest_paths.entry(PathBuf::new()).or_insert(Kind::Dir);
+        match dest_paths.get(&dest_path) {
+            Some(Kind::Dir) if kind == Kind::Dir => Ok(Some((dest_path, true))),
+            Some(_) => Err(Error::new(ErrorKind::MappedPathCollision, entry.path())),
+            None => {
+                dest_paths.insert(dest_path.clone(), kind);
+                Ok(Some((dest_path, false)))
+            }
+        }
+    }
+
+    /// Return true if a file, at a path relative to the top of the tree, should be written
+    /// by the [CopyOptions::transform_files] callback rather than copied.
+    fn should_transform(&mut self, path: &Path, entry: &Entry) -> bool {
+        match &mut self.transform_predicate {
+            Some(predicate) => entry.file_type().is_file() && predicate(path, entry),
+            None => false,
+        }
+    }
+
+    /// Write a file by passing the source, at `path` relative to the top of the tree, to the
+    /// [CopyOptions::transform_files] callback, returning the number of bytes written.
+    fn transform_file(
+        &mut self,
+        path: &Path,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        src: &Path,
+    ) -> Result<u64> {
+        let transform = self
+            .transform
+            .as_mut()
+            .expect("transform is set with its predicate");
+        let mut writer = CountingWriter { writer, bytes: 0 };
+        transform(path, reader, &mut writer)
+            .map_err(|io| Error::from_io_error(io, ErrorKind::TransformFile, src))?;
+        Ok(writer.bytes)
+    }
+
+    /// Return true if the file at `reference` can be hard linked in place of a copy of
+    /// `src`, according to [CopyOptions::link_compare].
+    ///
+    /// `owner` is the owner the copy would be given, from [CopyOptions::dest_owner].
+    /// Any error reading the reference means it doesn't match.
+    fn matches_reference(
+        &self,
+        src: &Path,
+        src_metadata: &Metadata,
+        owner: Option<(u32, u32)>,
+        reference: &Path,
+    ) -> bool {
+        let fs = &*self.file_system;
+        let reference_metadata = match fs.symlink_metadata(reference) {
+            Ok(metadata) if metadata.file_type.is_file() => metadata,
+            _ => return false,
+        };
+        if reference_metadata.len != src_metadata.len
+            || reference_metadata.mode != self.file_mode.apply(src_metadata.mode)
+        {
+            return false;
+        }
+        let matches = match self.link_compare {
+            LinkCompare::SizeAndMtime => reference_metadata.mtime == src_metadata.mtime,
+            LinkCompare::Content => same_content(fs, src, reference).unwrap_or(false),
+        };
+        match owner {
+            Some(owner) => matches && (reference_metadata.uid, reference_metadata.gid) == owner,
+            None => matches,
+        }
+    }
+
+    /// Return the uid and gid that a copy of an entry should be given, mapped by
+    /// [CopyOptions::map_uid] and [CopyOptions::map_gid], or None if owners aren't
+    /// preserved.
+    fn dest_owner(&mut self, src_metadata: &Metadata) -> Option<(u32, u32)> {
+        if !self.preserve_owner {
+            return None;
+        }
+        let mut uid = src_metadata.uid;
+        let mut gid = src_metadata.gid;
+        if let Some(map_uid) = &mut self.map_uid {
+            uid = map_uid(uid);
+        }
+        if let Some(map_gid) = &mut self.map_gid {
+            gid = map_gid(gid);
+        }
+        Some((uid, gid))
+    }
+
+    /// Copy the owner, extended attributes, ACLs, and timestamps from one entry to another,
+    /// as enabled by the options.
+    ///
+    /// `owner` is the owner to set, from [CopyOptions::dest_owner].
+    fn copy_entry_metadata(
+        &mut self,
+        src: &Path,
+        dest: &Path,
+        kind: Kind,
+        src_metadata: &Metadata,
+        owner: Option<(u32, u32)>,
+        stats: &mut CopyStats,
+    ) -> Result<()> {
+        // Set the owner first, because changing it can clear other attributes such as
+        // file capabilities.
+        if let Some(owner) = owner {
+            self.copy_owner(owner, src_metadata, dest, kind)?;
+        }
+        if self.copy_xattrs {
+            self.with_owner_write(dest, kind, ErrorKind::Xattr, || {
+                self.copy_entry_xattrs(src, dest, stats)
+            })?;
+        }
+        // Symlinks don't have ACLs. Setting ACLs only needs ownership, and temporarily
+        // changing the mode could change the ACL mask.
+        if self.copy_acls && kind != Kind::Symlink {
+            xattr::copy_xattrs(&*self.file_system, src, dest, ErrorKind::Acl, xattr::is_acl)?;
+        }
+        self.copy_times(src_metadata, dest, kind, stats)
+    }
+
+    /// Copy extended attributes and ACLs, as enabled by the options.
+    fn copy_entry_xattrs(&self, src: &Path, dest: &Path, stats: &mut CopyStats) -> Result<()> {
+        let namespaces = &self.xattr_namespaces;
+        match xattr::copy_xattrs(&*self.file_system, src, dest, ErrorKind::Xattr, |name| {
+            xattr::in_namespaces(name, namespaces)
+        }) {
+            Ok(_) => Ok(()),
+            Err(err) if self.xattr_errors == ErrorPolicy::Ignore => {
+                stats.add_ignored_error(err.kind());
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Run `f` with write permission for the owner temporarily added to `dest`, if it's
+    /// missing, because Linux only lets the owner set `user` extended attributes on files
+    /// and directories they can write.
+    ///
+    /// Errors reading the mode are reported as `error_kind`.
+    fn with_owner_write<F>(
+        &self,
+        dest: &Path,
+        kind: Kind,
+        error_kind: ErrorKind,
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        // Symlinks have no permissions of their own.
+        #[cfg(unix)]
+        if kind != Kind::Symlink {
+            let fs = &*self.file_system;
+            let mode = fs
+                .symlink_metadata(dest)
+                .map_err(|io| Error::from_io_error(io, error_kind, dest))?
+                .mode;
+            if mode & 0o200 == 0 {
+                set_mode(fs, dest, mode | 0o200)?;
+                let result = f();
+                let restored = set_mode(fs, dest, mode);
+                // An error from `f` is more interesting than one restoring the mode.
+                return result.and(restored);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (dest, kind, error_kind);
+        f()
+    }
+
+    /// Copy timestamps to a file or symlink.
+    ///
+    /// Directory timestamps are not copied, because they're changed by copying their
+    /// contents.
+    fn copy_times(
+        &self,
+        src_metadata: &Metadata,
+        dest: &Path,
+        kind: Kind,
+        stats: &mut CopyStats,
+    ) -> Result<()> {
+        let fs = &*self.file_system;
+        let mtime = src_metadata.mtime;
+        let result = match kind {
+            Kind::File if self.copy_atime => fs.set_times(dest, Some(src_metadata.atime), mtime),
+            Kind::File => fs.set_times(dest, None, mtime),
+            Kind::Symlink if self.copy_symlink_times => {
+                let atime = if self.copy_atime {
+                    Ok(src_metadata.atime)
+                } else {
+                    // Symlink times can't be set separately, so keep the current atime.
+                    fs.symlink_metadata(dest).map(|m| m.atime)
+                };
+                atime.and_then(|atime| fs.set_symlink_times(dest, atime, mtime))
+            }
+            Kind::Symlink | Kind::Dir => Ok(()),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(_) if self.time_errors == ErrorPolicy::Ignore => {
+                stats.add_ignored_error(ErrorKind::SetTimes);
+                Ok(())
+            }
+            Err(io) => Err(Error::from_io_error(io, ErrorKind::SetTimes, dest)),
+        }
+    }
+
+    /// Set the owner of a copy, to `owner` from [CopyOptions::dest_owner].
+    fn copy_owner(
+        &self,
+        (uid, gid): (u32, u32),
+        src_metadata: &Metadata,
+        dest: &Path,
+        kind: Kind,
+    ) -> Result<()> {
+        self.file_system
+            .set_owner(dest, uid, gid)
+            .map_err(|io| Error::from_io_error(io, ErrorKind::SetOwner, dest))?;
+        // Changing the owner clears the setuid and setgid bits, so put them back.
+        // (Directory permissions are set later.)
+        if kind == Kind::File {
+            let mode = self.file_mode.apply(src_metadata.mode);
+            if mode & 0o6000 != 0 {
+                set_mode(&*self.file_system, dest, mode)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How to set the Unix permissions of copied files or directories.
+///
+/// Modes are Unix permission bits such as `0o644`, including the setuid, setgid and
+/// sticky bits.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum ModeRule {
+    /// Give the destination the same mode as the source.
+    Preserve,
+    /// Set this mode, regardless of the mode of the source.
+    Fixed(u32),
+    /// Set the mode of the source combined with masks, as `(mode & and) | or`.
+    Mask {
+        /// Bits to keep from the source mode.
+        and: u32,
+        /// Bits to add.
+        or: u32,
+    },
+}
+
+impl ModeRule {
+    /// Return the mode to set on the destination, given the mode of the source.
+    ///
+    /// ```
+    /// use cp_r::ModeRule;
+    ///
+    /// assert_eq!(ModeRule::Preserve.apply(0o4750), 0o4750);
+    /// assert_eq!(ModeRule::Fixed(0o644).apply(0o4750), 0o644);
+    /// assert_eq!(ModeRule::Mask { and: !0o6000, or: 0o044 }.apply(0o4750), 0o754);
+    /// ```
+    pub fn apply(self, src_mode: u32) -> u32 {
+        let mode = match self {
+            ModeRule::Preserve => src_mode,
+            ModeRule::Fixed(mode) => mode,
+            ModeRule::Mask { and, or } => (src_mode & and) | or,
+        };
+        mode & 0o7777
     }
 }
 
-/// Counters of how many things were copied.
-#[derive(Debug, Default, PartialEq, Eq, Clone)]
+/// The type of an entry that was copied.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Kind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// How to sync copied data to disk.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum SyncMode {
+    /// Don't sync anything: the operating system will write data to disk in its own time.
+    None,
+    /// Sync each file after it is copied.
+    ///
+    /// The data of each file is durable, but the directory entries pointing to them
+    /// may not be.
+    Files,
+    /// Sync each file after it is copied, and each destination directory after all
+    /// its entries are created.
+    ///
+    /// Directories can't be synced on Windows, so this is the same as [SyncMode::Files]
+    /// there.
+    FilesAndDirs,
+    /// Sync the whole destination filesystem once, after everything is copied.
+    ///
+    /// This may be faster than syncing each file, but it also syncs any other data
+    /// pending on the same filesystem. On Linux this uses `syncfs`; on other Unix systems
+    /// it syncs all filesystems. It is not supported on Windows.
+    EndOnly,
+}
+
+/// How files are compared to a reference tree set by [CopyOptions::link_dest].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum LinkCompare {
+    /// Files match if they have the same size and mtime, without reading them.
+    SizeAndMtime,
+    /// Files match if they have the same size and content, which is read from both.
+    ///
+    /// The mtime is not compared, so a linked file keeps the mtime of the reference.
+    Content,
+}
+
+/// The order in which a tree is walked, set by [CopyOptions::traversal].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum Traversal {
+    /// Copy all the entries in each directory, and then the contents of its
+    /// subdirectories in turn.
+    BreadthFirst,
+    /// Copy the contents of each subdirectory as soon as it's created, before the rest of
+    /// the entries of its parent.
+    DepthFirst,
+}
+
+/// What to do when some optional metadata can't be copied.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum ErrorPolicy {
+    /// Stop copying and return the error.
+    Fail,
+    /// Ignore the error and continue copying.
+    Ignore,
+}
+
+/// Counters of how many things were copied, and how long it took.
+///
+/// More counters may be added in future releases, so this can't be constructed outside
+/// this crate, except through [Default].
+///
+/// Stats compare equal if their counters are equal: the times vary from run to run, so
+/// they're not compared.
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct CopyStats {
     /// The number of plain files copied.
     pub files: usize,
@@ -335,8 +1612,103 @@ pub struct CopyStats {
     pub symlinks: usize,
     /// The number of bytes of file content copied, across all files.
     pub file_bytes: u64,
+    /// The number of files hard linked to the reference tree set by
+    /// [CopyOptions::link_dest], rather than copied.
+    pub linked_files: usize,
     /// The number of entries filtered out by the [CopyOptions::filter] callback.
     pub filtered_out: usize,
+    /// The number of directories skipped because they're on a different filesystem
+    /// from the source, by [CopyOptions::one_file_system].
+    pub other_filesystem_dirs: usize,
+    /// The number of entries skipped because they're deeper than [CopyOptions::max_depth].
+    pub too_deep: usize,
+    /// The number of files skipped because they're larger than [CopyOptions::max_file_size].
+    pub too_large: usize,
+    /// The number of files copied that were empty.
+    pub empty_files: usize,
+    /// The path, relative to the top of the tree, of the largest file copied.
+    pub largest_file: Option<PathBuf>,
+    /// The size in bytes of the largest file copied.
+    pub largest_file_bytes: u64,
+    /// The number of errors of each kind that didn't stop the copy, as allowed by
+    /// [CopyOptions::xattr_errors] and [CopyOptions::time_errors].
+    pub ignored_errors: BTreeMap<ErrorKind, usize>,
+    /// The time taken by the copy so far.
+    pub elapsed: Duration,
+    /// The time spent listing source directories.
+    pub read_dir_time: Duration,
+    /// The time spent copying the content of files.
+    pub copy_file_time: Duration,
+}
+
+impl PartialEq for CopyStats {
+    fn eq(&self, other: &CopyStats) -> bool {
+        // Destructure so that new fields can't be forgotten here.
+        let CopyStats {
+            files,
+            dirs,
+            symlinks,
+            file_bytes,
+            linked_files,
+            filtered_out,
+            other_filesystem_dirs,
+            too_deep,
+            too_large,
+            empty_files,
+            largest_file,
+            largest_file_bytes,
+            ignored_errors,
+            elapsed: _,
+            read_dir_time: _,
+            copy_file_time: _,
+        } = self;
+        *files == other.files
+            && *dirs == other.dirs
+            && *symlinks == other.symlinks
+            && *file_bytes == other.file_bytes
+            && *linked_files == other.linked_files
+            && *filtered_out == other.filtered_out
+            && *other_filesystem_dirs == other.other_filesystem_dirs
+            && *too_deep == other.too_deep
+            && *too_large == other.too_large
+            && *empty_files == other.empty_files
+            && *largest_file == other.largest_file
+            && *largest_file_bytes == other.largest_file_bytes
+            && *ignored_errors == other.ignored_errors
+    }
+}
+
+impl Eq for CopyStats {}
+
+impl CopyStats {
+    /// The average rate of copying file content over the whole copy, in bytes per second.
+    ///
+    /// This is 0 if no time has elapsed.
+    pub fn bytes_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.file_bytes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Count a file that was copied, from a path relative to the top of the tree.
+    fn add_file(&mut self, path: &Path, bytes: u64) {
+        self.files += 1;
+        self.file_bytes += bytes;
+        if bytes == 0 {
+            self.empty_files += 1;
+        }
+        if self.largest_file.is_none() || bytes > self.largest_file_bytes {
+            self.largest_file = Some(path.to_owned());
+            self.largest_file_bytes = bytes;
+        }
+    }
+
+    fn add_ignored_error(&mut self, kind: ErrorKind) {
+        *self.ignored_errors.entry(kind).or_default() += 1;
+    }
 }
 
 /// An error from copying a tree.
@@ -368,10 +1740,18 @@ impl Error {
     }
 
     /// Construct a new error from a [std::io::Error].
+    ///
+    /// Errors from a hardened copy that found a symlink where it was not expected
+    /// have kind [ErrorKind::UnexpectedSymlink], regardless of `kind`.
     pub fn from_io_error<P>(io: io::Error, kind: ErrorKind, path: P) -> Error
     where
         P: Into<PathBuf>,
     {
+        let kind = if hardened::is_unexpected_symlink(&io) {
+            ErrorKind::UnexpectedSymlink
+        } else {
+            kind
+        };
         Error {
             path: path.into(),
             kind,
@@ -394,6 +1774,60 @@ impl Error {
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+
+    /// Describe the error in a form that can be serialized, for example to send to
+    /// monitoring as JSON.
+    #[cfg(feature = "serde")]
+    pub fn report(&self) -> ErrorReport {
+        ErrorReport {
+            path: self.path.clone(),
+            kind: self.kind,
+            io_error_kind: self.io.as_ref().map(|io| format!("{:?}", io.kind())),
+            message: self.to_string(),
+        }
+    }
+}
+
+/// A description of an [Error] that can be serialized and deserialized, returned by
+/// [Error::report].
+///
+/// This is only available with the `serde` feature. [Error] itself is serialized as its
+/// report.
+///
+/// ```
+/// use cp_r::{CopyOptions, ErrorKind};
+///
+/// let dest = tempfile::tempdir().unwrap();
+/// let err = CopyOptions::new()
+///     .copy_tree("nonexistent", &dest)
+///     .unwrap_err();
+/// let report = err.report();
+/// assert_eq!(report.kind, ErrorKind::ReadDir);
+/// assert_eq!(report.io_error_kind.as_deref(), Some("NotFound"));
+/// ```
+#[cfg(feature = "serde")]
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+#[non_exhaustive]
+pub struct ErrorReport {
+    /// The path where the error occurred.
+    pub path: PathBuf,
+    /// The kind of error.
+    pub kind: ErrorKind,
+    /// The kind of the underlying [std::io::Error], if any, as the name of its
+    /// [std::io::ErrorKind] such as `"NotFound"`.
+    pub io_error_kind: Option<String>,
+    /// The full description of the error, as it's displayed.
+    pub message: String,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.report().serialize(serializer)
+    }
 }
 
 impl std::error::Error for Error {
@@ -421,6 +1855,25 @@ impl fmt::Display for Error {
             UnsupportedFileType => "unsupported file type",
             CopyFile => "copying file",
             DestinationDoesNotExist => "destination directory does not exist",
+            Xattr => "copying extended attributes",
+            Acl => "copying ACLs",
+            SetOwner => "setting owner",
+            SetPermissions => "setting permissions",
+            SetTimes => "setting timestamps",
+            Sync => "syncing to disk",
+            ReadArchive => "reading archive",
+            UnsafePath => "unsafe path in archive",
+            UnexpectedSymlink => "unexpected symlink",
+            LimitExceeded(Limit::MaxFiles) => "too many files to copy",
+            LimitExceeded(Limit::MaxTotalBytes) => "too many bytes to copy",
+            LimitExceeded(_) => "limit exceeded",
+            DestinationInsideSource => "destination is inside source",
+            Cycle => "directory cycle",
+            InvalidMappedPath => "mapped path is outside the destination",
+            MappedPathCollision => "mapped path is already used by another entry",
+            TransformFile => "transforming file",
+            HardLink => "creating hard link",
+            ConflictingOptions => "conflicting copy options",
             Interrupted => "interrupted",
         };
         if let Some(io) = &self.io {
@@ -431,8 +1884,25 @@ impl fmt::Display for Error {
     }
 }
 
+/// A limit on what is copied, set by [CopyOptions].
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum Limit {
+    /// [CopyOptions::max_depth]: deeper entries are skipped.
+    MaxDepth,
+    /// [CopyOptions::max_files]: the copy stops with an error if there are more files.
+    MaxFiles,
+    /// [CopyOptions::max_total_bytes]: the copy stops with an error if there is more
+    /// file content.
+    MaxTotalBytes,
+    /// [CopyOptions::max_file_size]: larger files are skipped.
+    MaxFileSize,
+}
+
 /// Various kinds of errors that can occur while copying a tree.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum ErrorKind {
     /// Error listing a source directory.
@@ -456,41 +1926,265 @@ pub enum ErrorKind {
     DestinationDoesNotExist,
     /// The copy was interrupted by the user.
     ///
-    /// This is not currently generated internally by `cp_r` but can be returned
+    /// This is returned when an async copy is cancelled, and can also be returned
     /// by a callback.
     Interrupted,
+    /// Error reading or writing extended attributes.
+    Xattr,
+    /// Error reading or writing POSIX ACLs.
+    Acl,
+    /// Error setting the owner or group of a destination entry.
+    SetOwner,
+    /// Error setting the permissions of a destination entry.
+    SetPermissions,
+    /// Error setting the timestamps of a destination entry.
+    SetTimes,
+    /// Error syncing destination files or directories to disk.
+    Sync,
+    /// Error reading an archive that is the source of a copy.
+    ReadArchive,
+    /// An archive entry has an absolute path, contains `..`, or is inside or would replace
+    /// a symlink, so it could be written outside the destination.
+    UnsafePath,
+    /// A hardened copy found a symlink where it expected a directory or file, perhaps
+    /// because the tree was changed during the copy. See [CopyOptions::hardened].
+    UnexpectedSymlink,
+    /// Copying the tree would exceed a limit on the whole copy, such as
+    /// [CopyOptions::max_files].
+    LimitExceeded(Limit),
+    /// The destination directory is the source directory, or inside it.
+    ///
+    /// This is detected from device and inode numbers, which are only known on Unix and
+    /// in a [MemoryFileSystem].
+    DestinationInsideSource,
+    /// A source directory is the same as one of its parents, for example through a bind
+    /// mount, so copying it would never finish.
+    ///
+    /// Like [ErrorKind::DestinationInsideSource], this is only detected where device and
+    /// inode numbers are known.
+    Cycle,
+    /// The [CopyOptions::map_path] callback returned an absolute path or one containing
+    /// `..`, which could be outside the destination.
+    InvalidMappedPath,
+    /// The [CopyOptions::map_path] callback mapped an entry to the same path as an earlier
+    /// entry, other than two directories, which are merged.
+    MappedPathCollision,
+    /// The [CopyOptions::transform_files] callback returned an error.
+    TransformFile,
+    /// Error creating a hard link to the reference tree set by [CopyOptions::link_dest].
+    ///
+    /// The file is copied instead, so this is only counted in
+    /// [CopyStats::ignored_errors].
+    HardLink,
+    /// Options were set that can't be used together, such as [CopyOptions::hardened] with
+    /// a filesystem set by [CopyOptions::file_system], or with [CopyOptions::copy_xattrs].
+    ConflictingOptions,
 }
 
-fn copy_file(src: &Path, dest: &Path, stats: &mut CopyStats) -> Result<()> {
+/// Copy a file, returning the number of bytes copied.
+///
+/// If `sync` is true the file is synced before it's closed, and so before its
+/// permissions might stop it being reopened.
+fn copy_file(
+    fs: &dyn FileSystem,
+    src: &Path,
+    dest: &Path,
+    file_mode: ModeRule,
+    sync: bool,
+) -> Result<u64> {
     // TODO: Optionally first check and error if the destination exists.
-    let bytes_copied =
-        fs::copy(src, dest).map_err(|io| Error::from_io_error(io, ErrorKind::CopyFile, src))?;
-    stats.file_bytes += bytes_copied;
+    let bytes_copied = if file_mode == ModeRule::Preserve {
+        // The filesystem sets the permissions to match the source.
+        fs.copy_file(src, dest, sync)
+            .map_err(|io| Error::from_io_error(io, ErrorKind::CopyFile, src))?
+    } else {
+        copy_file_with_mode(fs, src, dest, file_mode, sync)?
+    };
+    Ok(bytes_copied)
+}
 
-    let src_metadata = src
-        .metadata()
+/// Copy file content to a new file and then set its mode.
+///
+/// The file is created with restricted permissions so that it's never visible with
+/// a mode broader than requested, for example with setuid bits that are to be stripped.
+fn copy_file_with_mode(
+    fs: &dyn FileSystem,
+    src: &Path,
+    dest: &Path,
+    file_mode: ModeRule,
+    sync: bool,
+) -> Result<u64> {
+    let read_err = |io| Error::from_io_error(io, ErrorKind::ReadFile, src);
+    let mode = file_mode.apply(fs.metadata(src).map_err(read_err)?.mode);
+    let mut reader = fs.open(src).map_err(read_err)?;
+    let write_err = |io| Error::from_io_error(io, ErrorKind::WriteFile, dest);
+    let mut writer = fs.create(dest, 0o600).map_err(write_err)?;
+    let bytes_copied = io::copy(&mut reader, &mut writer)
+        .map_err(|io| Error::from_io_error(io, ErrorKind::CopyFile, src))?;
+    writer.flush().map_err(write_err)?;
+    if sync {
+        sync_writer(&mut *writer, dest)?;
+    }
+    drop(writer);
+    set_mode(fs, dest, mode)?;
+    Ok(bytes_copied)
+}
+
+fn set_mode(fs: &dyn FileSystem, path: &Path, mode: u32) -> Result<()> {
+    fs.set_permissions(path, mode)
+        .map_err(|io| Error::from_io_error(io, ErrorKind::SetPermissions, path))
+}
+
+/// Set the mode of a newly written file from the mode of its source.
+///
+/// Preserving permissions is best-effort where they're not supported, as on Windows,
+/// where the failure is only counted in [CopyStats::ignored_errors].
+fn set_file_mode(
+    fs: &dyn FileSystem,
+    path: &Path,
+    src_mode: u32,
+    file_mode: ModeRule,
+    stats: &mut CopyStats,
+) -> Result<()> {
+    match fs.set_permissions(path, file_mode.apply(src_mode)) {
+        Err(io) if io.kind() == io::ErrorKind::Unsupported && file_mode == ModeRule::Preserve => {
+            stats.add_ignored_error(ErrorKind::SetPermissions);
+            Ok(())
+        }
+        Err(io) => Err(Error::from_io_error(io, ErrorKind::SetPermissions, path)),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Write a file through the [CopyOptions::transform_files] callback, and set its
+/// permissions, returning the number of bytes written.
+fn write_transformed_file(
+    options: &mut CopyOptions,
+    path: &Path,
+    src: &Path,
+    dest: &Path,
+    src_mode: u32,
+    sync: bool,
+    stats: &mut CopyStats,
+) -> Result<u64> {
+    let fs = &*options.file_system;
+    let mut reader = fs
+        .open(src)
         .map_err(|io| Error::from_io_error(io, ErrorKind::ReadFile, src))?;
-    let src_mtime = filetime::FileTime::from_last_modification_time(&src_metadata);
-    // It's OK if we can't set the mtime.
-    let _ = filetime::set_file_mtime(dest, src_mtime);
+    let write_err = |io| Error::from_io_error(io, ErrorKind::WriteFile, dest);
+    let mut writer = fs.create(dest, 0o600).map_err(write_err)?;
+    let bytes = options.transform_file(path, &mut reader, &mut writer, src)?;
+    writer.flush().map_err(write_err)?;
+    if sync {
+        sync_writer(&mut *writer, dest)?;
+    }
+    drop(writer);
+    set_file_mode(
+        &*options.file_system,
+        dest,
+        src_mode,
+        options.file_mode,
+        stats,
+    )?;
+    Ok(bytes)
+}
 
-    // Permissions should have already been set by fs::copy.
-    stats.files += 1;
-    Ok(())
+/// Return true if two files have the same content.
+fn same_content(fs: &dyn FileSystem, a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = fs.open(a)?;
+    let mut b = fs.open(b)?;
+    let mut a_buf = vec![0; 64 << 10];
+    let mut b_buf = vec![0; 64 << 10];
+    loop {
+        let len = read_fully(&mut a, &mut a_buf)?;
+        if read_fully(&mut b, &mut b_buf)? != len || a_buf[..len] != b_buf[..len] {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Read until the buffer is full or the end of the input, returning the number of bytes
+/// read.
+fn read_fully(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
 }
 
-fn copy_dir(_src: &Path, dest: &Path, stats: &mut CopyStats) -> Result<()> {
-    fs::create_dir(dest)
+/// Count the bytes written through to another writer.
+struct CountingWriter<'w> {
+    writer: &'w mut dyn Write,
+    bytes: u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.bytes += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Return the metadata of an entry listed from the source, reading it if it's not yet
+/// known.
+fn entry_metadata(entry: &Entry) -> Result<&Metadata> {
+    entry
+        .metadata()
+        .map_err(|io| Error::from_io_error(io, ErrorKind::ReadDir, entry.path()))
+}
+
+fn copy_dir(fs: &dyn FileSystem, dest: &Path, stats: &mut CopyStats) -> Result<()> {
+    fs.create_dir(dest)
         .map_err(|io| Error::from_io_error(io, ErrorKind::CreateDir, dest))
         .map(|()| stats.dirs += 1)
 }
 
-#[cfg(unix)]
-fn copy_symlink(src: &Path, dest: &Path, stats: &mut CopyStats) -> Result<()> {
-    let target =
-        fs::read_link(src).map_err(|io| Error::from_io_error(io, ErrorKind::ReadSymlink, src))?;
-    std::os::unix::fs::symlink(target, dest)
+fn copy_symlink(fs: &dyn FileSystem, src: &Path, dest: &Path, stats: &mut CopyStats) -> Result<()> {
+    let target = fs
+        .read_link(src)
+        .map_err(|io| Error::from_io_error(io, ErrorKind::ReadSymlink, src))?;
+    // Windows needs to know whether the target is a directory; a dangling link is
+    // made as a file link.
+    let target_is_dir = cfg!(windows)
+        && fs
+            .metadata(src)
+            .map(|m| m.file_type.is_dir())
+            .unwrap_or(false);
+    fs.symlink(&target, dest, target_is_dir)
         .map_err(|io| Error::from_io_error(io, ErrorKind::CreateSymlink, dest))?;
     stats.symlinks += 1;
     Ok(())
 }
+
+/// Sync the content of a file being written to disk.
+fn sync_writer(writer: &mut dyn WriteFile, path: &Path) -> Result<()> {
+    writer
+        .sync()
+        .map_err(|io| Error::from_io_error(io, ErrorKind::Sync, path))
+}
+
+/// Sync the entries in a directory to disk.
+fn sync_dir(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    fs.sync_dir(path)
+        .map_err(|io| Error::from_io_error(io, ErrorKind::Sync, path))
+}
+
+/// Sync the filesystem containing a path.
+fn sync_filesystem(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    fs.sync_filesystem(path)
+        .map_err(|io| Error::from_io_error(io, ErrorKind::Sync, path))
+}
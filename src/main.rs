@@ -0,0 +1,174 @@
+// Copyright 2024 Martin Pool
+
+//! `cp-r`: Copy a directory tree from the command line, with the same behavior as the
+//! `cp_r` library.
+//!
+//! This is only built with the `cli` feature.
+//!
+//! The exit code describes the kind of error, if any:
+//!
+//! | Code | Meaning |
+//! | ---- | ------- |
+//! | 0    | Success |
+//! | 1    | Other error |
+//! | 2    | Invalid command-line arguments |
+//! | 3    | The destination does not exist, and `--no-create-dest` was given, or is inside the source |
+//! | 4    | Error reading the source |
+//! | 5    | Error writing the destination |
+//! | 6    | Unsupported file type in the source |
+//! | 7    | Error copying metadata: permissions, owners, timestamps, xattrs or ACLs |
+//! | 8    | Error syncing to disk |
+//! | 130  | Interrupted |
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::Parser;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use cp_r::{CopyEvent, CopyOptions, CopyStats, Error, ErrorKind};
+
+/// Copy a directory tree, preserving mtimes and permissions.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Args {
+    /// The directory to copy from.
+    source: PathBuf,
+
+    /// The directory to copy to.
+    destination: PathBuf,
+
+    /// Fail if the destination directory doesn't already exist, rather than creating it.
+    #[arg(long)]
+    no_create_dest: bool,
+
+    /// Only copy files whose path relative to the source matches this glob. May be repeated.
+    ///
+    /// Directories are always copied, unless excluded, so that files inside them can
+    /// be included.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Don't copy files or directories whose path relative to the source matches this
+    /// glob. May be repeated.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Hard link files that are unchanged from the same path in this directory, such as a
+    /// previous backup, rather than copying them.
+    #[arg(long, value_name = "DIR")]
+    link_dest: Option<PathBuf>,
+
+    /// Skip directories on a different filesystem from the source.
+    #[arg(long, short = 'x')]
+    one_file_system: bool,
+
+    /// Print the path of each entry to stderr as it is copied.
+    #[arg(long, short)]
+    progress: bool,
+
+    /// Print counts of what was copied to stdout when the copy is done.
+    #[arg(long)]
+    stats: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let include = match build_glob_set(&args.include) {
+        Ok(set) => set,
+        Err(err) => return usage_error(err),
+    };
+    let exclude = match build_glob_set(&args.exclude) {
+        Ok(set) => set,
+        Err(err) => return usage_error(err),
+    };
+    let mut options = CopyOptions::new()
+        .create_destination(!args.no_create_dest)
+        .one_file_system(args.one_file_system);
+    if let Some(link_dest) = &args.link_dest {
+        options = options.link_dest(link_dest);
+    }
+    if !args.include.is_empty() || !args.exclude.is_empty() {
+        options = options.filter(move |path, entry| {
+            Ok(!exclude.is_match(path)
+                && (include.is_empty() || entry.file_type().is_dir() || include.is_match(path)))
+        });
+    }
+    let mut iter = options.into_iter(&args.source, &args.destination);
+    for event in &mut iter {
+        match event {
+            CopyEvent::Error(err) => {
+                eprintln!("cp-r: {}", err);
+                return ExitCode::from(exit_code(&err));
+            }
+            CopyEvent::DirCreated { path }
+            | CopyEvent::FileCopied { path, .. }
+            | CopyEvent::FileLinked { path }
+            | CopyEvent::SymlinkCreated { path }
+                if args.progress =>
+            {
+                eprintln!("{}", display_path(&args.destination, &path));
+            }
+            _ => (),
+        }
+    }
+    if args.stats {
+        print_stats(iter.stats());
+    }
+    ExitCode::SUCCESS
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+fn usage_error(err: globset::Error) -> ExitCode {
+    eprintln!("cp-r: invalid pattern: {}", err);
+    ExitCode::from(2)
+}
+
+/// Show a path relative to the top of the tree as the full destination path.
+fn display_path(dest: &Path, path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        dest.display().to_string()
+    } else {
+        dest.join(path).display().to_string()
+    }
+}
+
+fn print_stats(stats: &CopyStats) {
+    println!("files: {}", stats.files);
+    println!("dirs: {}", stats.dirs);
+    println!("symlinks: {}", stats.symlinks);
+    println!("file_bytes: {}", stats.file_bytes);
+    println!("linked_files: {}", stats.linked_files);
+    println!("filtered_out: {}", stats.filtered_out);
+    println!("other_filesystem_dirs: {}", stats.other_filesystem_dirs);
+    println!("too_deep: {}", stats.too_deep);
+    println!("too_large: {}", stats.too_large);
+    println!("empty_files: {}", stats.empty_files);
+    println!("largest_file_bytes: {}", stats.largest_file_bytes);
+    println!("elapsed_secs: {:.3}", stats.elapsed.as_secs_f64());
+    println!("bytes_per_second: {:.0}", stats.bytes_per_second());
+}
+
+/// Map an error to the process exit code, as described in the module docs.
+fn exit_code(err: &Error) -> u8 {
+    use ErrorKind::*;
+    match err.kind() {
+        DestinationDoesNotExist | DestinationInsideSource => 3,
+        ReadDir | ReadFile | ReadSymlink | ReadArchive | UnsafePath | UnexpectedSymlink | Cycle => {
+            4
+        }
+        WriteFile | CopyFile | CreateDir | CreateSymlink => 5,
+        UnsupportedFileType => 6,
+        Xattr | Acl | SetOwner | SetPermissions | SetTimes => 7,
+        Sync => 8,
+        Interrupted => 130,
+        _ => 1,
+    }
+}
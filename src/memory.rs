@@ -0,0 +1,421 @@
+// Copyright 2024 Martin Pool
+
+//! A filesystem held in memory, for testing.
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use crate::filesystem::{Entry, FileSystem, FileType, Metadata, ReadDir, WriteFile};
+
+/// The maximum number of symlinks followed in resolving a path, as on Linux.
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// The next inode number to give a node, unique across all memory filesystems.
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+/// A filesystem held in memory, which can be used to test copying without creating
+/// real files.
+///
+/// Clones of a `MemoryFileSystem` share the same contents, so a clone can be passed
+/// to [CopyOptions::file_system](crate::CopyOptions::file_system) and the result
+/// inspected through the original.
+///
+/// The filesystem starts with just an empty root directory `/`. Relative paths are
+/// treated as relative to the root.
+///
+/// Symlinks are followed only when they are the last component of a path, by
+/// [FileSystem::metadata] and [FileSystem::open].
+///
+/// ```
+/// use std::path::Path;
+/// use cp_r::{CopyOptions, FileSystem, MemoryFileSystem};
+///
+/// let fs = MemoryFileSystem::new();
+/// fs.create_dir(Path::new("/src")).unwrap();
+/// fs.write_file("/src/hello", b"hello world").unwrap();
+///
+/// let stats = CopyOptions::new()
+///     .file_system(fs.clone())
+///     .copy_tree("/src", "/dest")
+///     .unwrap();
+///
+/// assert_eq!(stats.files, 1);
+/// assert_eq!(fs.read_file("/dest/hello").unwrap(), b"hello world");
+/// ```
+#[derive(Debug, Clone)]
+pub struct MemoryFileSystem {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    content: Content,
+    metadata: Metadata,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+enum Content {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+impl Node {
+    fn new(content: Content, mode: u32) -> Node {
+        let file_type = match content {
+            Content::File(_) => FileType::File,
+            Content::Dir => FileType::Dir,
+            Content::Symlink(_) => FileType::Symlink,
+        };
+        let mut metadata = Metadata::new(file_type);
+        metadata.mode = mode;
+        metadata.ino = NEXT_INO.fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now();
+        metadata.atime = now;
+        metadata.mtime = now;
+        Node {
+            content,
+            metadata,
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        let mut metadata = self.metadata.clone();
+        metadata.len = match &self.content {
+            Content::File(data) => data.len() as u64,
+            Content::Dir => 0,
+            Content::Symlink(target) => target.as_os_str().len() as u64,
+        };
+        metadata
+    }
+}
+
+impl Default for MemoryFileSystem {
+    fn default() -> Self {
+        MemoryFileSystem::new()
+    }
+}
+
+impl MemoryFileSystem {
+    /// Construct a new filesystem containing just an empty root directory.
+    pub fn new() -> MemoryFileSystem {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), Node::new(Content::Dir, 0o755));
+        MemoryFileSystem {
+            nodes: Arc::new(Mutex::new(nodes)),
+        }
+    }
+
+    /// Write a file, replacing it if it already exists.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P, content: &[u8]) -> io::Result<()> {
+        self.create(path.as_ref(), 0o644)?.write_all(content)
+    }
+
+    /// Return the content of a file, following symlinks.
+    pub fn read_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open(path.as_ref())?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Set the device id of a file or directory, without following symlinks.
+    ///
+    /// This can simulate another filesystem mounted inside the tree, to test
+    /// [CopyOptions::one_file_system](crate::CopyOptions::one_file_system).
+    pub fn set_dev<P: AsRef<Path>>(&self, path: P, dev: u64) -> io::Result<()> {
+        self.with_node(path.as_ref(), |node| {
+            node.metadata.dev = dev;
+            Ok(())
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Node>> {
+        // If another thread panicked while holding the lock, it's still safe to use the
+        // nodes: each operation leaves them consistent.
+        self.nodes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Insert a new node, after checking the parent is a directory and the path
+    /// doesn't already exist.
+    fn insert_new(&self, path: &Path, node: Node) -> io::Result<()> {
+        let path = normalize(path)?;
+        let mut nodes = self.lock();
+        check_parent_is_dir(&nodes, &path)?;
+        if nodes.contains_key(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", path),
+            ));
+        }
+        nodes.insert(path, node);
+        Ok(())
+    }
+
+    /// Apply a function to the node at a path, without following symlinks.
+    fn with_node<F, T>(&self, path: &Path, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut Node) -> io::Result<T>,
+    {
+        let path = normalize(path)?;
+        let mut nodes = self.lock();
+        match nodes.get_mut(&path) {
+            Some(node) => f(node),
+            None => Err(not_found(&path)),
+        }
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let path = normalize(path)?;
+        let nodes = self.lock();
+        match nodes.get(&path) {
+            None => return Err(not_found(&path)),
+            Some(Node {
+                content: Content::Dir,
+                ..
+            }) => (),
+            Some(_) => return Err(not_a_directory(&path)),
+        }
+        // Paths sort after their parent and before the parent's next sibling, so the
+        // descendants of `path` are a contiguous range of the map.
+        let entries: Vec<io::Result<Entry>> = nodes
+            .range::<PathBuf, _>((Excluded(&path), Unbounded))
+            .take_while(|(child_path, _)| child_path.starts_with(&path))
+            .filter(|(child_path, _)| child_path.parent() == Some(&path))
+            .map(|(child_path, node)| Ok(Entry::new(child_path.clone(), node.metadata())))
+            .collect();
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let nodes = self.lock();
+        let path = resolve(&nodes, path)?;
+        Ok(nodes[&path].metadata())
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.with_node(path, |node| Ok(node.metadata()))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.insert_new(path, Node::new(Content::Dir, 0o755))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let nodes = self.lock();
+        let path = resolve(&nodes, path)?;
+        match &nodes[&path].content {
+            Content::File(data) => Ok(Box::new(io::Cursor::new(data.clone()))),
+            Content::Dir => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{:?} is a directory", path),
+            )),
+            Content::Symlink(_) => unreachable!("symlinks are resolved"),
+        }
+    }
+
+    fn create(&self, path: &Path, mode: u32) -> io::Result<Box<dyn WriteFile>> {
+        let path = normalize(path)?;
+        let mut nodes = self.lock();
+        check_parent_is_dir(&nodes, &path)?;
+        match nodes.get_mut(&path) {
+            Some(Node {
+                content: content @ Content::File(_),
+                ..
+            }) => *content = Content::File(Vec::new()),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} exists and is not a file", path),
+                ))
+            }
+            None => {
+                nodes.insert(path.clone(), Node::new(Content::File(Vec::new()), mode));
+            }
+        }
+        Ok(Box::new(MemoryWriter {
+            fs: self.clone(),
+            path,
+        }))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.with_node(path, |node| match &node.content {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a symlink".to_owned(),
+            )),
+        })
+    }
+
+    fn symlink(&self, target: &Path, link: &Path, _target_is_dir: bool) -> io::Result<()> {
+        self.insert_new(link, Node::new(Content::Symlink(target.to_owned()), 0o777))
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.with_node(path, |node| {
+            node.metadata.mode = mode & 0o7777;
+            Ok(())
+        })
+    }
+
+    fn set_times(
+        &self,
+        path: &Path,
+        atime: Option<SystemTime>,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        self.with_node(path, |node| {
+            if let Some(atime) = atime {
+                node.metadata.atime = atime;
+            }
+            node.metadata.mtime = mtime;
+            Ok(())
+        })
+    }
+
+    fn set_symlink_times(
+        &self,
+        path: &Path,
+        atime: SystemTime,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        self.set_times(path, Some(atime), mtime)
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        self.with_node(path, |node| {
+            node.metadata.uid = uid;
+            node.metadata.gid = gid;
+            Ok(())
+        })
+    }
+
+    fn list_xattrs(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        self.with_node(path, |node| Ok(node.xattrs.keys().cloned().collect()))
+    }
+
+    fn get_xattr(&self, path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        self.with_node(path, |node| Ok(node.xattrs.get(name).cloned()))
+    }
+
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        self.with_node(path, |node| {
+            node.xattrs.insert(name.to_owned(), value.to_owned());
+            Ok(())
+        })
+    }
+}
+
+/// Appends to a file in a [MemoryFileSystem].
+struct MemoryWriter {
+    fs: MemoryFileSystem,
+    path: PathBuf,
+}
+
+impl WriteFile for MemoryWriter {}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.fs
+            .with_node(&self.path, |node| match &mut node.content {
+                Content::File(data) => {
+                    data.extend_from_slice(buf);
+                    node.metadata.mtime = SystemTime::now();
+                    Ok(buf.len())
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "file was replaced while writing".to_owned(),
+                )),
+            })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Turn a path into the key used in the map: absolute, with no `.` components.
+fn normalize(path: &Path) -> io::Result<PathBuf> {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported path {:?}", path),
+                ))
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+/// Follow symlinks in the last component of a path, and return the key of an
+/// existing node that is not a symlink.
+fn resolve(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> io::Result<PathBuf> {
+    let mut path = normalize(path)?;
+    for _ in 0..MAX_SYMLINK_FOLLOWS {
+        match nodes.get(&path) {
+            None => return Err(not_found(&path)),
+            Some(Node {
+                content: Content::Symlink(target),
+                ..
+            }) => {
+                // Relative targets are relative to the directory containing the link.
+                path = normalize(&path.parent().unwrap_or(&path).join(target))?;
+            }
+            Some(_) => return Ok(path),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("too many levels of symlinks resolving {:?}", path),
+    ))
+}
+
+fn check_parent_is_dir(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the root directory already exists",
+            ))
+        }
+    };
+    match nodes.get(parent) {
+        Some(Node {
+            content: Content::Dir,
+            ..
+        }) => Ok(()),
+        Some(_) => Err(not_a_directory(parent)),
+        None => Err(not_found(parent)),
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path))
+}
+
+fn not_a_directory(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("{:?} is not a directory", path),
+    )
+}
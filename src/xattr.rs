@@ -0,0 +1,160 @@
+// Copyright 2024 Martin Pool
+
+//! Copy extended attributes, and POSIX ACLs which are stored in them.
+//!
+//! Extended attributes are read and written through the [FileSystem]. On the real
+//! filesystem they are currently only supported on Linux: on other platforms,
+//! asking to copy them returns an [io::ErrorKind::Unsupported] error.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+
+use crate::{Error, ErrorKind, FileSystem, Result};
+
+/// The attributes in which Linux stores the access and default POSIX ACLs.
+const ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Copy extended attributes whose names are accepted by `select` from `src` to `dest`.
+///
+/// Symlinks are not followed: the attributes of the link itself are copied.
+///
+/// Errors are reported with the given `kind`.
+///
+/// Returns the number of attributes copied.
+pub(crate) fn copy_xattrs<F>(
+    fs: &dyn FileSystem,
+    src: &Path,
+    dest: &Path,
+    kind: ErrorKind,
+    mut select: F,
+) -> Result<usize>
+where
+    F: FnMut(&OsStr) -> bool,
+{
+    let read_err = |io| Error::from_io_error(io, kind, src);
+    let mut copied = 0;
+    for name in fs.list_xattrs(src).map_err(read_err)? {
+        if !select(&name) {
+            continue;
+        }
+        // The attribute might have been removed since we listed it; that's not an error.
+        if let Some(value) = fs.get_xattr(src, &name).map_err(read_err)? {
+            fs.set_xattr(dest, &name, &value)
+                .map_err(|io| Error::from_io_error(io, kind, dest))?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Return true if the attribute name is in one of the given namespaces.
+///
+/// For example `user.checksum` is in the `user` namespace.
+pub(crate) fn in_namespaces(name: &OsStr, namespaces: &[String]) -> bool {
+    let name = name.to_string_lossy();
+    match name.split_once('.') {
+        Some((ns, _)) => namespaces.iter().any(|n| n == ns),
+        None => false,
+    }
+}
+
+/// Return true if the attribute holds a POSIX ACL.
+pub(crate) fn is_acl(name: &OsStr) -> bool {
+    ACL_XATTRS.iter().any(|acl| name == *acl)
+}
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"))
+}
+
+#[cfg(target_os = "linux")]
+fn c_name(name: &OsStr) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(name.as_bytes()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "attribute name contains a nul byte",
+        )
+    })
+}
+
+/// Call a `*xattr` function that fills a buffer, growing the buffer until the value fits.
+#[cfg(target_os = "linux")]
+fn read_into_vec<F>(mut f: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
+{
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let len = f(buf.as_mut_ptr().cast(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+        // The value grew between the two calls; try again.
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn list(path: &Path) -> io::Result<Vec<std::ffi::OsString>> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = c_path(path)?;
+    let names = match read_into_vec(|buf, size| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf.cast(), size)
+    }) {
+        Ok(names) => names,
+        // The filesystem doesn't support xattrs, so there are none to copy.
+        Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    Ok(names
+        .split(|b| *b == 0)
+        .filter(|n| !n.is_empty())
+        .map(|n| OsStr::from_bytes(n).to_owned())
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn get(path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+    let c_path = c_path(path)?;
+    let c_name = c_name(name)?;
+    match read_into_vec(|buf, size| unsafe {
+        libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf, size)
+    }) {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set(path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let c_path = c_path(path)?;
+    let c_name = c_name(name)?;
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
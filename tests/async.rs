@@ -0,0 +1,94 @@
+// Copyright 2024 Martin Pool
+
+//! Test copying trees asynchronously.
+
+#![cfg(feature = "async")]
+
+use std::cell::RefCell;
+use std::fs::{create_dir, read, write};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+
+use cp_r::*;
+
+#[tokio::test]
+async fn copy_tree_async() {
+    let src = tempfile::tempdir().unwrap();
+    create_dir(src.path().join("a")).unwrap();
+    write(src.path().join("a/file"), b"hello").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let stats = CopyOptions::copy_tree_async(CopyOptions::new, src.path(), dest.path())
+        .await
+        .unwrap();
+
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.dirs, 1);
+    assert_eq!(read(dest.path().join("a/file")).unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn async_error_is_returned() {
+    let dest = tempfile::tempdir().unwrap();
+    let err = CopyOptions::copy_tree_async(CopyOptions::new, "nothing", dest.path())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ReadDir);
+}
+
+#[tokio::test]
+async fn drop_future_to_cancel() {
+    let src = tempfile::tempdir().unwrap();
+    for i in 0..100 {
+        write(src.path().join(format!("file{i}")), b"hello").unwrap();
+    }
+    let dest = tempfile::tempdir().unwrap();
+
+    let (started_tx, started_rx) = mpsc::channel::<()>();
+    let (resume_tx, resume_rx) = mpsc::channel::<()>();
+    let copied = Arc::new(AtomicUsize::new(0));
+    let callback_copied = Arc::clone(&copied);
+    let future = CopyOptions::copy_tree_async(
+        move || {
+            CopyOptions::new().after_entry_copied(move |_path, _file_type, _stats| {
+                callback_copied.fetch_add(1, Ordering::Relaxed);
+                started_tx.send(()).unwrap();
+                // Wait until the test has dropped the future.
+                let _ = resume_rx.recv();
+                Ok(())
+            })
+        },
+        src.path(),
+        dest.path(),
+    );
+
+    started_rx.recv().unwrap();
+    drop(future);
+    drop(resume_tx);
+    // The sender is dropped along with the callback when the copy task finishes.
+    while started_rx.recv().is_ok() {}
+    assert_eq!(copied.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn async_callbacks_need_not_be_send() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"hello").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let stats = CopyOptions::copy_tree_async(
+        || {
+            let seen = Rc::new(RefCell::new(Vec::new()));
+            CopyOptions::new().filter(move |path, _entry| {
+                seen.borrow_mut().push(path.to_owned());
+                Ok(true)
+            })
+        },
+        src.path(),
+        dest.path(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(stats.files, 1);
+}
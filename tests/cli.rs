@@ -0,0 +1,140 @@
+// Copyright 2024 Martin Pool
+
+//! Test the `cp-r` command-line tool.
+
+#![cfg(feature = "cli")]
+
+use std::fs::{create_dir, read, write};
+use std::process::Command;
+
+fn cp_r() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_cp-r"))
+}
+
+#[test]
+fn copy_with_stats_and_progress() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"hello").unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("sub/b"), b"world!").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let dest_path = dest.path().join("copy");
+
+    let output = cp_r()
+        .args(["--stats", "--progress"])
+        .arg(src.path())
+        .arg(&dest_path)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("files: 2\n"), "{}", stdout);
+    assert!(stdout.contains("dirs: 2\n"), "{}", stdout);
+    assert!(stdout.contains("file_bytes: 11\n"), "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.lines().count(), 4, "{}", stderr);
+    assert_eq!(read(dest_path.join("sub/b")).unwrap(), b"world!");
+}
+
+#[test]
+fn copy_current_dir_to_sibling() {
+    let tmp = tempfile::tempdir().unwrap();
+    let src = tmp.path().join("src");
+    create_dir(&src).unwrap();
+    write(src.join("a"), b"hello").unwrap();
+
+    let output = cp_r()
+        .current_dir(&src)
+        .args([".", "../x"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(read(tmp.path().join("x/a")).unwrap(), b"hello");
+}
+
+#[cfg(unix)]
+#[test]
+fn link_dest_links_unchanged_files() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"hello").unwrap();
+    let backups = tempfile::tempdir().unwrap();
+    let first = backups.path().join("first");
+    let second = backups.path().join("second");
+    assert!(cp_r()
+        .arg(src.path())
+        .arg(&first)
+        .status()
+        .unwrap()
+        .success());
+
+    let output = cp_r()
+        .args(["--stats", "--link-dest"])
+        .arg(&first)
+        .arg(src.path())
+        .arg(&second)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("files: 0\n"), "{}", stdout);
+    assert!(stdout.contains("linked_files: 1\n"), "{}", stdout);
+    assert_eq!(read(second.join("a")).unwrap(), b"hello");
+}
+
+#[test]
+fn include_and_exclude_patterns() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("keep.txt"), b"").unwrap();
+    write(src.path().join("skip.log"), b"").unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("sub/keep.txt"), b"").unwrap();
+    create_dir(src.path().join("target")).unwrap();
+    write(src.path().join("target/keep.txt"), b"").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let status = cp_r()
+        .args(["--include", "**/*.txt", "--exclude", "target"])
+        .arg(src.path())
+        .arg(dest.path())
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert!(dest.path().join("keep.txt").exists());
+    assert!(dest.path().join("sub/keep.txt").exists());
+    assert!(!dest.path().join("skip.log").exists());
+    assert!(!dest.path().join("target").exists());
+}
+
+#[test]
+fn missing_destination_exit_code() {
+    let src = tempfile::tempdir().unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let output = cp_r()
+        .arg("--no-create-dest")
+        .arg(src.path())
+        .arg(dest.path().join("nonexistent"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("destination directory does not exist"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn missing_source_exit_code() {
+    let dest = tempfile::tempdir().unwrap();
+    let status = cp_r()
+        .arg(dest.path().join("nonexistent"))
+        .arg(dest.path().join("copy"))
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(4));
+}
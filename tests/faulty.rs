@@ -0,0 +1,126 @@
+// Copyright 2024 Martin Pool
+
+//! Test error handling by injecting faults with [FaultyFileSystem].
+
+use std::io;
+use std::path::Path;
+
+use cp_r::*;
+
+mod util;
+use util::*;
+
+/// Make a source tree in memory with a few files in a subdirectory.
+fn setup_src() -> MemoryFileSystem {
+    make_memory_tree(&[
+        ("sub/0", b"content"),
+        ("sub/1", b"content"),
+        ("sub/2", b"content"),
+        ("sub/3", b"content"),
+        ("sub/4", b"content"),
+    ])
+}
+
+#[test]
+fn read_dir_permission_denied() {
+    let fs = FaultyFileSystem::new(setup_src()).fail(
+        Fault::new(Operation::ReadDir)
+            .path("**/sub")
+            .error_kind(io::ErrorKind::PermissionDenied),
+    );
+    let err = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ReadDir);
+    assert_eq!(err.path(), Path::new("/src/sub"));
+    assert_eq!(
+        err.io_error().unwrap().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(fs.faults_injected(), 1);
+    // The top directory was read, so its subdirectory was created.
+    assert!(fs.inner().metadata(Path::new("/dest/sub")).is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn nth_write_fails_with_os_error() {
+    let fs = FaultyFileSystem::new(setup_src())
+        .fail(Fault::new(Operation::Write).nth(3).os_error(libc::ENOSPC));
+    let mut iter = CopyOptions::new()
+        .file_system(fs.clone())
+        .into_iter("/src", "/dest");
+    let err = iter
+        .by_ref()
+        .find_map(|event| match event {
+            CopyEvent::Error(err) => Some(err),
+            _ => None,
+        })
+        .expect("copy failed");
+    assert_eq!(err.kind(), ErrorKind::CopyFile);
+    assert_eq!(err.io_error().unwrap().raw_os_error(), Some(libc::ENOSPC));
+    assert_eq!(iter.stats().files, 2);
+    assert_eq!(fs.faults_injected(), 1);
+}
+
+#[test]
+fn faults_only_match_their_operation_and_path() {
+    let fs = FaultyFileSystem::new(setup_src())
+        .fail(Fault::new(Operation::Symlink))
+        .fail(Fault::new(Operation::CopyFile).path("/src/other/**"));
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/dest")
+        .unwrap();
+    assert_eq!(stats.files, 5);
+    assert_eq!(fs.faults_injected(), 0);
+}
+
+#[test]
+fn sync_failure_is_reported() {
+    for file_mode in [ModeRule::Preserve, ModeRule::Fixed(0o200)] {
+        let fs = FaultyFileSystem::new(setup_src())
+            .fail(Fault::new(Operation::Sync).path("/dest/sub/2"));
+        let err = CopyOptions::new()
+            .file_system(fs.clone())
+            .file_mode(file_mode)
+            .sync(SyncMode::Files)
+            .copy_tree("/src", "/dest")
+            .unwrap_err();
+        assert_eq!(fs.faults_injected(), 1, "{:?}", file_mode);
+        if file_mode != ModeRule::Preserve {
+            // Otherwise the sync is part of FileSystem::copy_file.
+            assert_eq!(err.kind(), ErrorKind::Sync);
+            assert_eq!(err.path(), Path::new("/dest/sub/2"));
+        }
+    }
+}
+
+#[test]
+fn set_permissions_failure_on_real_filesystem() {
+    let src = tempfile::tempdir().unwrap();
+    std::fs::write(src.path().join("file"), b"hello").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let fs = FaultyFileSystem::new(StdFileSystem)
+        .fail(Fault::new(Operation::SetPermissions).error_kind(io::ErrorKind::PermissionDenied));
+    let err = CopyOptions::new()
+        .file_system(fs)
+        .file_mode(ModeRule::Fixed(0o644))
+        .copy_tree(&src, &dest)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::SetPermissions);
+    assert_eq!(err.path(), dest.path().join("file"));
+}
+
+#[test]
+fn ignored_errors_are_counted() {
+    let fs = FaultyFileSystem::new(setup_src()).fail(Fault::new(Operation::SetTimes));
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/dest")
+        .unwrap();
+    assert_eq!(stats.files, 5);
+    assert_eq!(stats.ignored_errors.get(&ErrorKind::SetTimes), Some(&5));
+    assert_eq!(stats.ignored_errors.len(), 1);
+}
@@ -0,0 +1,201 @@
+// Copyright 2024 Martin Pool
+
+//! Test that hardened copies don't follow symlinks planted in the tree during the copy.
+
+#![cfg(unix)]
+
+use std::fs::{create_dir, read, read_link, remove_dir, rename, write};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+use cp_r::*;
+
+#[test]
+fn hardened_copy_of_ordinary_tree() {
+    let src = tempfile::tempdir().unwrap();
+    create_dir(src.path().join("a")).unwrap();
+    create_dir(src.path().join("a/b")).unwrap();
+    write(src.path().join("a/b/file"), b"content").unwrap();
+    symlink("b/file", src.path().join("a/link")).unwrap();
+    // The destination path itself is trusted, even though it's a symlink.
+    let tmp = tempfile::tempdir().unwrap();
+    let real_dest = tmp.path().join("real");
+    create_dir(&real_dest).unwrap();
+    let dest = tmp.path().join("dest");
+    symlink(&real_dest, &dest).unwrap();
+
+    let stats = CopyOptions::new()
+        .hardened(true)
+        .copy_tree(src.path(), &dest)
+        .unwrap();
+
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.dirs, 2);
+    assert_eq!(stats.symlinks, 1);
+    assert_eq!(read(real_dest.join("a/b/file")).unwrap(), b"content");
+    assert_eq!(
+        read_link(real_dest.join("a/link")).unwrap(),
+        Path::new("b/file")
+    );
+}
+
+#[test]
+fn symlink_planted_in_destination_directory() {
+    let src = tempfile::tempdir().unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("sub/file"), b"content").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+
+    let planted_dir = dest.path().join("sub");
+    let outside_path = outside.path().to_owned();
+    let err = CopyOptions::new()
+        .hardened(true)
+        .filter(move |path, _entry| {
+            if path == Path::new("sub/file") {
+                // After `dest/sub` is created, replace it by a link out of the tree.
+                remove_dir(&planted_dir).unwrap();
+                symlink(&outside_path, &planted_dir).unwrap();
+            }
+            Ok(true)
+        })
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::UnexpectedSymlink);
+    assert!(!outside.path().join("file").exists());
+}
+
+#[test]
+fn symlink_planted_at_destination_file() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("victim"), b"content").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+
+    let planted_link = dest.path().join("victim");
+    let target = outside.path().join("target");
+    let err = CopyOptions::new()
+        .hardened(true)
+        .filter(move |_path, _entry| {
+            symlink(&target, &planted_link).unwrap();
+            Ok(true)
+        })
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::UnexpectedSymlink);
+    assert!(!outside.path().join("target").exists());
+}
+
+#[test]
+fn symlink_planted_in_source_directory() {
+    let src = tempfile::tempdir().unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    write(outside.path().join("secret"), b"secret").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let src_sub = src.path().join("sub");
+    let moved = src.path().join("moved");
+    let outside_path = outside.path().to_owned();
+    let err = CopyOptions::new()
+        .hardened(true)
+        .after_entry_copied(move |path, _file_type, _stats| {
+            if path == Path::new("sub") {
+                // Before `src/sub` is read, replace it by a link out of the tree.
+                rename(&src_sub, &moved).unwrap();
+                symlink(&outside_path, &src_sub).unwrap();
+            }
+            Ok(())
+        })
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::UnexpectedSymlink);
+    assert!(!dest.path().join("sub/secret").exists());
+}
+
+#[test]
+fn hardened_copy_links_to_reference() {
+    use std::os::unix::fs::MetadataExt;
+
+    let src = tempfile::tempdir().unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("sub/file"), b"content").unwrap();
+    let reference = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .copy_tree(src.path(), reference.path())
+        .unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let stats = CopyOptions::new()
+        .hardened(true)
+        .link_dest(reference.path())
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    assert_eq!(stats.linked_files, 1);
+    assert_eq!(
+        std::fs::metadata(dest.path().join("sub/file"))
+            .unwrap()
+            .ino(),
+        std::fs::metadata(reference.path().join("sub/file"))
+            .unwrap()
+            .ino()
+    );
+}
+
+#[test]
+fn hardened_copy_refuses_custom_file_system() {
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    fs.write_file("/src/a", b"alpha").unwrap();
+
+    let err = CopyOptions::new()
+        .file_system(fs.clone())
+        .hardened(true)
+        .copy_tree("/src", "/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConflictingOptions);
+    assert!(fs.metadata(Path::new("/dest")).is_err());
+}
+
+#[test]
+fn hardened_copy_refuses_xattrs_and_acls() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"alpha").unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let dest = tmp.path().join("dest");
+
+    for options in [
+        CopyOptions::new().copy_xattrs(true),
+        CopyOptions::new().copy_acls(true),
+    ] {
+        let err = options
+            .hardened(true)
+            .copy_tree(src.path(), &dest)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConflictingOptions);
+        assert!(!dest.exists());
+    }
+}
+
+#[test]
+fn hardened_copy_sets_write_only_mode() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"alpha").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    CopyOptions::new()
+        .hardened(true)
+        .file_mode(ModeRule::Fixed(0o200))
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    let mode = std::fs::metadata(dest.path().join("a"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o200);
+}
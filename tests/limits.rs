@@ -0,0 +1,129 @@
+// Copyright 2024 Martin Pool
+
+//! Test limits on the depth and size of a copy.
+
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use cp_r::*;
+
+mod util;
+use util::*;
+
+/// Make a tree with files at depths 1, 2 and 3.
+fn setup_src() -> TempDir {
+    make_tree(&[
+        ("top", b"0123456789"),
+        ("a/middle", b"01234"),
+        ("a/b/bottom", b"0"),
+    ])
+}
+
+#[test]
+fn max_depth_skips_deeper_entries() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+
+    let stats = CopyOptions::new()
+        .max_depth(2)
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.dirs, 2);
+    assert_eq!(stats.too_deep, 1);
+    assert!(dest.path().join("a/middle").is_file());
+    assert!(dest.path().join("a/b").is_dir());
+    assert!(!dest.path().join("a/b/bottom").exists());
+}
+
+#[test]
+fn max_file_size_skips_large_files() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+
+    let mut skipped = Vec::new();
+    for event in CopyOptions::new()
+        .max_file_size(5)
+        .into_iter(src.path(), dest.path())
+    {
+        match event {
+            CopyEvent::SkippedByLimit { path, limit } => skipped.push((path, limit)),
+            CopyEvent::Error(err) => panic!("copy failed: {}", err),
+            _ => (),
+        }
+    }
+
+    assert_eq!(skipped, [(Path::new("top").to_owned(), Limit::MaxFileSize)]);
+    assert!(!dest.path().join("top").exists());
+    assert!(dest.path().join("a/middle").is_file());
+}
+
+#[test]
+fn max_files_stops_the_copy() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+
+    let err = CopyOptions::new()
+        .max_files(2)
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::LimitExceeded(Limit::MaxFiles));
+    assert_eq!(err.path(), src.path().join("a/b/bottom"));
+    assert!(!dest.path().join("a/b/bottom").exists());
+}
+
+#[test]
+fn max_total_bytes_stops_the_copy() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+
+    let err = CopyOptions::new()
+        .max_total_bytes(12)
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::LimitExceeded(Limit::MaxTotalBytes));
+    assert_eq!(err.path(), src.path().join("a/middle"));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "too many bytes to copy: {}",
+            src.path().join("a/middle").display()
+        )
+    );
+
+    // Exactly enough bytes is fine.
+    let dest = tempfile::tempdir().unwrap();
+    let stats = CopyOptions::new()
+        .max_total_bytes(16)
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+    assert_eq!(stats.file_bytes, 16);
+}
+
+#[test]
+fn max_total_bytes_counts_transformed_output() {
+    let src = make_tree(&[("a", b"1234"), ("b", b"1234")]);
+    let dest = tempfile::tempdir().unwrap();
+
+    // Each file is checked by its source size, but counts its doubled output once written.
+    let err = CopyOptions::new()
+        .sort_by_name()
+        .max_total_bytes(10)
+        .transform_files(
+            |_path, _entry| true,
+            |_path, reader, writer| {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                writer.write_all(&content)?;
+                writer.write_all(&content)
+            },
+        )
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::LimitExceeded(Limit::MaxTotalBytes));
+    assert_eq!(err.path(), src.path().join("b"));
+    assert_eq!(std::fs::read(dest.path().join("a")).unwrap(), b"12341234");
+}
@@ -0,0 +1,220 @@
+// Copyright 2024 Martin Pool
+
+//! Test hard linking unchanged files to a reference tree.
+
+use std::fs::{create_dir, read, write};
+use std::path::Path;
+
+use filetime::{set_file_mtime, FileTime};
+use tempfile::TempDir;
+
+use cp_r::*;
+
+/// Return true if two paths are hard links to the same file.
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let a = std::fs::metadata(a).unwrap();
+    let b = std::fs::metadata(b).unwrap();
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+/// Make a source tree and a backup of it.
+fn setup() -> (TempDir, TempDir) {
+    let src = tempfile::tempdir().unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("unchanged"), b"same").unwrap();
+    write(src.path().join("sub/changed"), b"old").unwrap();
+    let backups = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .copy_tree(src.path(), backups.path().join("1"))
+        .unwrap();
+    write(src.path().join("sub/changed"), b"new").unwrap();
+    set_file_mtime(
+        src.path().join("sub/changed"),
+        FileTime::from_unix_time(1_700_000_000, 0),
+    )
+    .unwrap();
+    write(src.path().join("added"), b"added").unwrap();
+    (src, backups)
+}
+
+#[cfg(unix)]
+#[test]
+fn unchanged_files_are_linked() {
+    let (src, backups) = setup();
+    let reference = backups.path().join("1");
+    let dest = backups.path().join("2");
+
+    let mut linked = Vec::new();
+    let mut iter = CopyOptions::new()
+        .link_dest(&reference)
+        .into_iter(src.path(), &dest);
+    for event in &mut iter {
+        match event {
+            CopyEvent::FileLinked { path } => linked.push(path),
+            CopyEvent::Error(err) => panic!("copy failed: {}", err),
+            _ => (),
+        }
+    }
+    let stats = iter.into_stats();
+
+    assert_eq!(linked, [Path::new("unchanged")]);
+    assert_eq!(stats.linked_files, 1);
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.file_bytes, 8);
+    assert!(same_file(
+        &reference.join("unchanged"),
+        &dest.join("unchanged")
+    ));
+    assert!(!same_file(
+        &reference.join("sub/changed"),
+        &dest.join("sub/changed")
+    ));
+    assert_eq!(read(dest.join("sub/changed")).unwrap(), b"new");
+    assert_eq!(read(reference.join("sub/changed")).unwrap(), b"old");
+    assert_eq!(read(dest.join("added")).unwrap(), b"added");
+}
+
+#[cfg(unix)]
+#[test]
+fn linked_files_are_not_limited() {
+    let (src, backups) = setup();
+    let reference = backups.path().join("1");
+    let dest = backups.path().join("2");
+
+    // The unchanged file is reached last, when the copied files have used up the limits.
+    let stats = CopyOptions::new()
+        .sort_by_name()
+        .traversal(Traversal::DepthFirst)
+        .max_files(2)
+        .max_total_bytes(8)
+        .link_dest(&reference)
+        .copy_tree(src.path(), &dest)
+        .unwrap();
+
+    assert_eq!(stats.linked_files, 1);
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.file_bytes, 8);
+    assert!(same_file(
+        &reference.join("unchanged"),
+        &dest.join("unchanged")
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn different_permissions_are_not_linked() {
+    let (src, backups) = setup();
+    let reference = backups.path().join("1");
+    let dest = backups.path().join("2");
+
+    let stats = CopyOptions::new()
+        .link_dest(&reference)
+        .file_mode(ModeRule::Fixed(0o600))
+        .copy_tree(src.path(), &dest)
+        .unwrap();
+
+    assert_eq!(stats.linked_files, 0);
+    assert_eq!(stats.files, 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn compare_content() {
+    let (src, backups) = setup();
+    let reference = backups.path().join("1");
+    // Same content with a different mtime, and different content with the same size and
+    // mtime.
+    set_file_mtime(
+        src.path().join("unchanged"),
+        FileTime::from_unix_time(1_700_000_000, 0),
+    )
+    .unwrap();
+    set_file_mtime(
+        reference.join("sub/changed"),
+        FileTime::from_unix_time(1_700_000_000, 0),
+    )
+    .unwrap();
+
+    let by_mtime = backups.path().join("2");
+    CopyOptions::new()
+        .link_dest(&reference)
+        .copy_tree(src.path(), &by_mtime)
+        .unwrap();
+    assert!(!same_file(
+        &reference.join("unchanged"),
+        &by_mtime.join("unchanged")
+    ));
+    assert_eq!(read(by_mtime.join("sub/changed")).unwrap(), b"old");
+
+    let by_content = backups.path().join("3");
+    let stats = CopyOptions::new()
+        .link_dest(&reference)
+        .link_compare(LinkCompare::Content)
+        .copy_tree(src.path(), &by_content)
+        .unwrap();
+    assert_eq!(stats.linked_files, 1);
+    assert!(same_file(
+        &reference.join("unchanged"),
+        &by_content.join("unchanged")
+    ));
+    assert_eq!(read(by_content.join("sub/changed")).unwrap(), b"new");
+}
+
+#[test]
+fn unsupported_links_fall_back_to_copying() {
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    fs.write_file("/src/a", b"alpha").unwrap();
+    CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/backup")
+        .unwrap();
+
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .link_dest("/backup")
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    assert_eq!(stats.linked_files, 0);
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.ignored_errors[&ErrorKind::HardLink], 1);
+    assert_eq!(fs.read_file("/dest/a").unwrap(), b"alpha");
+}
+
+#[test]
+fn owner_is_mapped_once_per_entry() {
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    fs.write_file("/src/a", b"hello").unwrap();
+    CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/reference")
+        .unwrap();
+
+    // The file matches the reference except for its mapped owner, so it's copied.
+    let mut map_uid_calls = 0;
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .link_dest("/reference")
+        .preserve_owner(true)
+        .map_uid(|uid| {
+            map_uid_calls += 1;
+            uid + 1
+        })
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    assert_eq!(stats.linked_files, 0);
+    assert_eq!(stats.files, 1);
+    assert_eq!(
+        map_uid_calls, 2,
+        "once for the top directory and once for the file"
+    );
+    assert_eq!(
+        fs.metadata(Path::new("/dest/a")).unwrap().uid,
+        fs.metadata(Path::new("/src/a")).unwrap().uid + 1
+    );
+}
@@ -0,0 +1,55 @@
+// Copyright 2024 Martin Pool
+
+//! Test the tracing events emitted during a copy.
+
+#![cfg(feature = "tracing")]
+
+use std::fs::{create_dir, write};
+
+use tracing_test::traced_test;
+
+use cp_r::*;
+
+#[test]
+#[traced_test]
+fn copy_emits_events_in_spans() {
+    let src = tempfile::tempdir().unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("sub/file"), b"hello").unwrap();
+    write(src.path().join("skipped"), b"").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    CopyOptions::new()
+        .filter(|path, _entry| Ok(path.file_name().unwrap() != "skipped"))
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    assert!(logs_contain("copy_tree{src="));
+    assert!(logs_contain(
+        "copy_dir{path=sub}: cp_r::iter: copied file path=sub/file bytes=5"
+    ));
+    assert!(logs_contain("filtered out path=skipped"));
+    assert!(logs_contain(
+        "copy finished files=1 dirs=1 symlinks=0 file_bytes=5"
+    ));
+}
+
+#[test]
+#[traced_test]
+fn copy_failure_is_logged() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("file"), b"hello").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let err = CopyOptions::new()
+        .max_files(0)
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.path(), src.path().join("file"));
+    assert!(logs_contain(&format!(
+        "copy failed path={} kind=LimitExceeded(MaxFiles) error={}",
+        err.path().display(),
+        err
+    )));
+}
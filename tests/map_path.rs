@@ -0,0 +1,115 @@
+// Copyright 2024 Martin Pool
+
+//! Test mapping source paths to different destination paths.
+
+use std::fs::{read, write};
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+use cp_r::*;
+
+mod util;
+use util::*;
+
+fn setup_src() -> TempDir {
+    make_tree(&[
+        ("top.txt", b"top"),
+        ("a/middle.txt", b"middle"),
+        ("a/b/bottom.txt", b"bottom"),
+    ])
+}
+
+#[test]
+fn flatten_directories() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+
+    let mut merged = Vec::new();
+    for event in CopyOptions::new()
+        .map_path(|path, entry| {
+            Ok(Some(if entry.file_type().is_dir() {
+                PathBuf::new()
+            } else {
+                path.file_name().unwrap().into()
+            }))
+        })
+        .into_iter(src.path(), dest.path())
+    {
+        match event {
+            CopyEvent::DirMerged { path } => merged.push(path),
+            CopyEvent::Error(err) => panic!("copy failed: {}", err),
+            _ => (),
+        }
+    }
+
+    assert_eq!(merged, [Path::new("a"), Path::new("a/b")]);
+    assert_eq!(read(dest.path().join("top.txt")).unwrap(), b"top");
+    assert_eq!(read(dest.path().join("middle.txt")).unwrap(), b"middle");
+    assert_eq!(read(dest.path().join("bottom.txt")).unwrap(), b"bottom");
+    assert!(!dest.path().join("a").exists());
+}
+
+#[test]
+fn change_extensions_and_skip() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+
+    let stats = CopyOptions::new()
+        .map_path(|path, _entry| {
+            if path == Path::new("a/b") {
+                Ok(None)
+            } else if path.extension().is_some_and(|ext| ext == "txt") {
+                Ok(Some(path.with_extension("md")))
+            } else {
+                Ok(Some(path.to_owned()))
+            }
+        })
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.filtered_out, 1);
+    assert!(dest.path().join("top.md").is_file());
+    assert!(dest.path().join("a/middle.md").is_file());
+    assert!(!dest.path().join("a/b").exists());
+    assert!(!dest.path().join("top.txt").exists());
+}
+
+#[test]
+fn collision_is_an_error() {
+    let src = setup_src();
+    write(src.path().join("TOP.TXT"), b"shouting").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let err = CopyOptions::new()
+        .sort_by_name()
+        .map_path(|path, _entry| Ok(Some(path.to_str().unwrap().to_lowercase().into())))
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::MappedPathCollision);
+    assert_eq!(err.path(), src.path().join("top.txt"));
+    assert_eq!(read(dest.path().join("top.txt")).unwrap(), b"shouting");
+}
+
+#[test]
+fn path_outside_destination_is_an_error() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+
+    let err = CopyOptions::new()
+        .map_path(|path, _entry| Ok(Some(Path::new("..").join(path))))
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::InvalidMappedPath);
+    assert!(err.path().starts_with(src.path()));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "mapped path is outside the destination: {}",
+            err.path().display()
+        )
+    );
+}
@@ -0,0 +1,368 @@
+// Copyright 2024 Martin Pool
+
+//! Test copying within a [MemoryFileSystem].
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use cp_r::*;
+
+mod util;
+use util::*;
+
+fn setup_src() -> MemoryFileSystem {
+    let fs = make_memory_tree(&[("a", b"alpha"), ("sub/b", b"beta!")]);
+    fs.symlink(Path::new("sub/b"), Path::new("/src/link"), false)
+        .unwrap();
+    fs
+}
+
+#[test]
+fn copy_tree_in_memory() {
+    let fs = setup_src();
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/dest")
+        .unwrap();
+    assert_eq!(
+        stats,
+        stats_with(|s| {
+            s.files = 2;
+            s.dirs = 2;
+            s.symlinks = 1;
+            s.file_bytes = 10;
+            s.largest_file = Some(PathBuf::from("a"));
+            s.largest_file_bytes = 5;
+        })
+    );
+    assert_eq!(fs.read_file("/dest/a").unwrap(), b"alpha");
+    assert_eq!(fs.read_file("/dest/sub/b").unwrap(), b"beta!");
+    assert_eq!(
+        fs.read_link(Path::new("/dest/link")).unwrap(),
+        Path::new("sub/b")
+    );
+    // Reading through the link finds the copied file.
+    assert_eq!(fs.read_file("/dest/link").unwrap(), b"beta!");
+    // Nothing was written to the real filesystem.
+    assert!(!Path::new("/dest").exists());
+}
+
+#[test]
+fn metadata_is_copied_in_memory() {
+    let fs = setup_src();
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let atime = UNIX_EPOCH + Duration::from_secs(1_200_000_000);
+    fs.set_times(Path::new("/src/a"), Some(atime), mtime)
+        .unwrap();
+    fs.set_permissions(Path::new("/src/a"), 0o640).unwrap();
+    fs.set_owner(Path::new("/src/a"), 1234, 5678).unwrap();
+    fs.set_xattr(Path::new("/src/a"), "user.tag".as_ref(), b"v1")
+        .unwrap();
+
+    CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_atime(true)
+        .copy_xattrs(true)
+        .preserve_owner(true)
+        .map_uid(|uid| uid + 1)
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    let dest_metadata = fs.metadata(Path::new("/dest/a")).unwrap();
+    assert_eq!(dest_metadata.mtime, mtime);
+    assert_eq!(dest_metadata.atime, atime);
+    assert_eq!(dest_metadata.mode, 0o640);
+    assert_eq!(dest_metadata.uid, 1235);
+    assert_eq!(dest_metadata.gid, 5678);
+    assert_eq!(
+        fs.get_xattr(Path::new("/dest/a"), "user.tag".as_ref())
+            .unwrap(),
+        Some(b"v1".to_vec())
+    );
+}
+
+#[test]
+fn filter_sees_entries_from_file_system() {
+    let fs = setup_src();
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .filter(|path, entry| {
+            assert_eq!(entry.path(), Path::new("/src").join(path));
+            Ok(!entry.file_type().is_symlink())
+        })
+        .copy_tree("/src", "/dest")
+        .unwrap();
+    assert_eq!(stats.filtered_out, 1);
+    assert_eq!(stats.symlinks, 0);
+    assert!(fs.symlink_metadata(Path::new("/dest/link")).is_err());
+}
+
+#[test]
+fn missing_destination_parent_is_an_error() {
+    let fs = setup_src();
+    let err = CopyOptions::new()
+        .file_system(fs)
+        .copy_tree("/src", "/nonexistent/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::CreateDir);
+    assert_eq!(err.path(), Path::new("/nonexistent/dest"));
+}
+
+#[test]
+fn read_dir_lists_only_children() {
+    let fs = setup_src();
+    fs.create_dir(Path::new("/src0")).unwrap();
+    fs.write_file("/src0/c", b"gamma").unwrap();
+    fs.write_file("/s", b"delta").unwrap();
+
+    let mut names: Vec<PathBuf> = fs
+        .read_dir(Path::new("/src"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["a", "link", "sub"].map(PathBuf::from));
+}
+
+#[test]
+fn one_file_system_skips_other_devices() {
+    let fs = setup_src();
+    fs.create_dir(Path::new("/src/mnt")).unwrap();
+    fs.write_file("/src/mnt/remote", b"far away").unwrap();
+    fs.set_dev("/src/mnt", 7).unwrap();
+
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .one_file_system(true)
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    assert_eq!(stats.other_filesystem_dirs, 1);
+    assert_eq!(stats.dirs, 2);
+    assert_eq!(stats.files, 2);
+    assert!(fs.symlink_metadata(Path::new("/dest/sub")).is_ok());
+    assert!(fs.symlink_metadata(Path::new("/dest/mnt")).is_err());
+
+    // By default, other filesystems are copied.
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/dest2")
+        .unwrap();
+    assert_eq!(stats.other_filesystem_dirs, 0);
+    assert_eq!(fs.read_file("/dest2/mnt/remote").unwrap(), b"far away");
+}
+
+#[test]
+fn filtered_out_dirs_are_not_counted_as_other_filesystems() {
+    let fs = setup_src();
+    fs.create_dir(Path::new("/src/mnt")).unwrap();
+    fs.set_dev("/src/mnt", 7).unwrap();
+
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .one_file_system(true)
+        .filter(|path, _entry| Ok(path != Path::new("mnt")))
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    assert_eq!(stats.filtered_out, 1);
+    assert_eq!(stats.other_filesystem_dirs, 0);
+}
+
+#[test]
+fn copy_into_itself_in_memory_fails() {
+    let fs = setup_src();
+    let err = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/src/sub/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::DestinationInsideSource);
+    assert!(fs.symlink_metadata(Path::new("/src/sub/dest")).is_err());
+
+    // A sibling with a similar name is fine.
+    CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree("/src", "/src2")
+        .unwrap();
+}
+
+/// A filesystem that reports the directory `alias` as the same directory as `target`, as
+/// if it were a bind mount.
+struct AliasFileSystem {
+    fs: MemoryFileSystem,
+    alias: &'static str,
+    target: &'static str,
+}
+
+impl FileSystem for AliasFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let target_ino = self.fs.metadata(Path::new(self.target))?.ino;
+        let alias = self.alias;
+        Ok(Box::new(self.fs.read_dir(path)?.map(move |entry| {
+            let entry = entry?;
+            if entry.path() != Path::new(alias) {
+                return Ok(entry);
+            }
+            let mut metadata = entry.metadata()?.clone();
+            metadata.ino = target_ino;
+            Ok(Entry::new(entry.path().to_owned(), metadata))
+        })))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.fs.create_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        self.fs.open(path)
+    }
+
+    fn create(&self, path: &Path, mode: u32) -> io::Result<Box<dyn WriteFile>> {
+        self.fs.create(path, mode)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.fs.read_link(path)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path, target_is_dir: bool) -> io::Result<()> {
+        self.fs.symlink(target, link, target_is_dir)
+    }
+}
+
+#[test]
+fn directory_cycle_is_an_error() {
+    let fs = setup_src();
+    fs.create_dir(Path::new("/src/sub/loop")).unwrap();
+
+    let err = CopyOptions::new()
+        .file_system(AliasFileSystem {
+            fs: fs.clone(),
+            alias: "/src/sub/loop",
+            target: "/src",
+        })
+        .copy_tree("/src", "/dest")
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::Cycle);
+    assert_eq!(err.path(), Path::new("/src/sub/loop"));
+    assert!(fs.symlink_metadata(Path::new("/dest/sub/loop")).is_err());
+}
+
+#[test]
+fn directory_reached_twice_without_cycle() {
+    let fs = setup_src();
+    fs.create_dir(Path::new("/src/other")).unwrap();
+    fs.create_dir(Path::new("/src/other/mount")).unwrap();
+
+    // The same directory is mounted in two places, but neither is inside the other.
+    let stats = CopyOptions::new()
+        .file_system(AliasFileSystem {
+            fs: fs.clone(),
+            alias: "/src/other/mount",
+            target: "/src/sub",
+        })
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    assert_eq!(stats.dirs, 4);
+    assert!(fs.symlink_metadata(Path::new("/dest/other/mount")).is_ok());
+}
+
+/// Like Linux for an unprivileged user, refuses to set extended attributes on files the
+/// owner can't write.
+struct OwnerWriteXattrFileSystem(MemoryFileSystem);
+
+impl FileSystem for OwnerWriteXattrFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        self.0.read_dir(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.0.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.0.symlink_metadata(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.0.create_dir(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        self.0.open(path)
+    }
+
+    fn create(&self, path: &Path, mode: u32) -> io::Result<Box<dyn WriteFile>> {
+        self.0.create(path, mode)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.0.read_link(path)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path, target_is_dir: bool) -> io::Result<()> {
+        self.0.symlink(target, link, target_is_dir)
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.0.set_permissions(path, mode)
+    }
+
+    fn set_times(
+        &self,
+        path: &Path,
+        atime: Option<std::time::SystemTime>,
+        mtime: std::time::SystemTime,
+    ) -> io::Result<()> {
+        self.0.set_times(path, atime, mtime)
+    }
+
+    fn list_xattrs(&self, path: &Path) -> io::Result<Vec<std::ffi::OsString>> {
+        self.0.list_xattrs(path)
+    }
+
+    fn get_xattr(&self, path: &Path, name: &std::ffi::OsStr) -> io::Result<Option<Vec<u8>>> {
+        self.0.get_xattr(path, name)
+    }
+
+    fn set_xattr(&self, path: &Path, name: &std::ffi::OsStr, value: &[u8]) -> io::Result<()> {
+        if self.0.symlink_metadata(path)?.mode & 0o200 == 0 {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        self.0.set_xattr(path, name, value)
+    }
+}
+
+#[test]
+fn xattrs_copied_to_read_only_file() {
+    for mode in [0o444, 0o000] {
+        let fs = setup_src();
+        fs.set_xattr(Path::new("/src/a"), "user.test".as_ref(), b"value")
+            .unwrap();
+        fs.set_permissions(Path::new("/src/a"), mode).unwrap();
+
+        CopyOptions::new()
+            .file_system(OwnerWriteXattrFileSystem(fs.clone()))
+            .copy_xattrs(true)
+            .copy_tree("/src", "/dest")
+            .unwrap();
+
+        assert_eq!(
+            fs.get_xattr(Path::new("/dest/a"), "user.test".as_ref())
+                .unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(fs.metadata(Path::new("/dest/a")).unwrap().mode, mode);
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2024 Martin Pool
+
+//! Test the order in which entries are copied.
+
+use std::path::PathBuf;
+
+use tempfile::TempDir;
+
+use cp_r::*;
+
+mod util;
+use util::*;
+
+fn setup_src() -> TempDir {
+    make_tree(&[
+        ("c", b""),
+        ("a", b""),
+        ("b/f", b""),
+        ("b/e", b""),
+        ("b/d/g", b""),
+    ])
+}
+
+/// Copy the tree, returning the paths of the entries in the order they're copied.
+fn copied_paths(options: CopyOptions) -> Vec<String> {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+    options
+        .into_iter(src.path(), dest.path())
+        .map(|event| match event {
+            CopyEvent::FileCopied { path, .. } | CopyEvent::DirCreated { path } => path,
+            CopyEvent::Error(err) => panic!("copy failed: {}", err),
+            other => panic!("unexpected event {:?}", other),
+        })
+        .map(|path: PathBuf| path.to_str().unwrap().replace('\\', "/"))
+        .collect()
+}
+
+#[test]
+fn sorted_breadth_first() {
+    assert_eq!(
+        copied_paths(CopyOptions::new().sort_by_name()),
+        ["a", "b", "c", "b/d", "b/e", "b/f", "b/d/g"]
+    );
+}
+
+#[test]
+fn sorted_depth_first() {
+    assert_eq!(
+        copied_paths(
+            CopyOptions::new()
+                .sort_by_name()
+                .traversal(Traversal::DepthFirst)
+        ),
+        ["a", "b", "b/d", "b/d/g", "b/e", "b/f", "c"]
+    );
+}
+
+#[test]
+fn sorted_by_custom_comparison() {
+    assert_eq!(
+        copied_paths(
+            CopyOptions::new()
+                .sort_by(|a, b| b.file_name().cmp(a.file_name()))
+                .traversal(Traversal::DepthFirst)
+        ),
+        ["c", "b", "b/f", "b/e", "b/d", "b/d/g", "a"]
+    );
+}
+
+#[test]
+fn unsorted_depth_first_copies_everything() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+    let stats = CopyOptions::new()
+        .traversal(Traversal::DepthFirst)
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+    assert_eq!(stats.files, 5);
+    assert_eq!(stats.dirs, 2);
+    assert!(dest.path().join("b/d/g").is_file());
+}
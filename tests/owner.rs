@@ -0,0 +1,127 @@
+// Copyright 2024 Martin Pool
+
+//! Test preserving and remapping file owners.
+
+#![cfg(unix)]
+
+use std::fs::symlink_metadata;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::Path;
+
+use cp_r::*;
+
+mod util;
+use util::*;
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn lchown(path: &Path, uid: u32, gid: u32) {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::lchown(c_path.as_ptr(), uid, gid) }, 0);
+}
+
+fn owner(path: &Path) -> (u32, u32) {
+    let metadata = symlink_metadata(path).unwrap();
+    (metadata.uid(), metadata.gid())
+}
+
+fn setup_src() -> tempfile::TempDir {
+    let src = make_tree(&[("file", b"content"), ("dir/", b"")]);
+    symlink("file", src.path().join("link")).unwrap();
+    src
+}
+
+#[test]
+fn preserve_own_ids() {
+    // This works without any special privileges because the owner doesn't change.
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .preserve_owner(true)
+        .copy_tree(&src, &dest)
+        .unwrap();
+    for name in ["file", "dir", "link"] {
+        assert_eq!(
+            owner(&dest.path().join(name)),
+            owner(&src.path().join(name))
+        );
+    }
+}
+
+#[test]
+fn preserve_other_owner_as_root() {
+    if !is_root() {
+        println!("skipping test that needs to run as root");
+        return;
+    }
+    let src = setup_src();
+    for name in ["file", "dir", "link"] {
+        lchown(&src.path().join(name), 1234, 5678);
+    }
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .preserve_owner(true)
+        .copy_tree(&src, &dest)
+        .unwrap();
+    for name in ["file", "dir", "link"] {
+        assert_eq!(owner(&dest.path().join(name)), (1234, 5678));
+    }
+}
+
+#[test]
+fn map_ids_as_root() {
+    if !is_root() {
+        println!("skipping test that needs to run as root");
+        return;
+    }
+    let src = setup_src();
+    lchown(&src.path().join("file"), 1000, 100);
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .preserve_owner(true)
+        .map_uid(|uid| uid + 100000)
+        .map_gid(|gid| gid + 100000)
+        .copy_tree(&src, &dest)
+        .unwrap();
+    assert_eq!(owner(&dest.path().join("file")), (101000, 100100));
+}
+
+#[test]
+fn owner_not_changed_by_default() {
+    if !is_root() {
+        return;
+    }
+    let src = setup_src();
+    lchown(&src.path().join("file"), 1234, 5678);
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new().copy_tree(&src, &dest).unwrap();
+    assert_eq!(owner(&dest.path().join("file")), (0, 0));
+}
+
+#[test]
+fn setuid_bit_kept_after_changing_owner() {
+    use std::fs::{metadata, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    if !is_root() {
+        return;
+    }
+    let src = setup_src();
+    let src_file = src.path().join("file");
+    lchown(&src_file, 1234, 5678);
+    set_permissions(&src_file, Permissions::from_mode(0o4755)).unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .preserve_owner(true)
+        .copy_tree(&src, &dest)
+        .unwrap();
+    let dest_file = dest.path().join("file");
+    assert_eq!(owner(&dest_file), (1234, 5678));
+    assert_eq!(
+        metadata(&dest_file).unwrap().permissions().mode() & 0o7777,
+        0o4755
+    );
+}
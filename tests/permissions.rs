@@ -0,0 +1,128 @@
+// Copyright 2024 Martin Pool
+
+//! Test setting and masking Unix permissions.
+
+#![cfg(unix)]
+
+use std::fs::{metadata, set_permissions, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use cp_r::*;
+
+mod util;
+use util::*;
+
+fn mode(path: &Path) -> u32 {
+    metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+fn set_mode(path: &Path, mode: u32) {
+    set_permissions(path, Permissions::from_mode(mode)).unwrap();
+}
+
+fn setup_src() -> tempfile::TempDir {
+    let src = make_tree(&[
+        ("private", b"secret"),
+        ("setuid", b"#!/bin/sh\n"),
+        ("dir/file", b"content"),
+    ]);
+    set_mode(&src.path().join("private"), 0o600);
+    set_mode(&src.path().join("setuid"), 0o4750);
+    set_mode(&src.path().join("dir/file"), 0o640);
+    set_mode(&src.path().join("dir"), 0o750);
+    src
+}
+
+#[test]
+fn file_modes_preserved_by_default() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new().copy_tree(&src, &dest).unwrap();
+    assert_eq!(mode(&dest.path().join("private")), 0o600);
+    assert_eq!(mode(&dest.path().join("setuid")), 0o4750);
+    assert_eq!(mode(&dest.path().join("dir/file")), 0o640);
+}
+
+#[test]
+fn fixed_file_mode() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+    let stats = CopyOptions::new()
+        .file_mode(ModeRule::Fixed(0o644))
+        .copy_tree(&src, &dest)
+        .unwrap();
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.file_bytes, 6 + 10 + 7);
+    assert_eq!(mode(&dest.path().join("private")), 0o644);
+    assert_eq!(mode(&dest.path().join("setuid")), 0o644);
+    assert_eq!(mode(&dest.path().join("dir/file")), 0o644);
+    assert_eq!(
+        std::fs::read(dest.path().join("private")).unwrap(),
+        b"secret"
+    );
+}
+
+#[test]
+fn sync_files_that_cannot_be_reopened() {
+    for file_mode in [0o200, 0o000] {
+        let src = setup_src();
+        let dest = tempfile::tempdir().unwrap();
+        CopyOptions::new()
+            .file_mode(ModeRule::Fixed(file_mode))
+            .sync(SyncMode::Files)
+            .copy_tree(&src, &dest)
+            .unwrap();
+        assert_eq!(mode(&dest.path().join("private")), file_mode);
+        assert_eq!(mode(&dest.path().join("dir/file")), file_mode);
+    }
+}
+
+#[test]
+fn mask_strips_setuid_and_adds_read() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .file_mode(ModeRule::Mask {
+            and: !0o6000,
+            or: 0o044,
+        })
+        .dir_mode(ModeRule::Mask {
+            and: !0o6000,
+            or: 0o055,
+        })
+        .copy_tree(&src, &dest)
+        .unwrap();
+    assert_eq!(mode(&dest.path().join("private")), 0o644);
+    assert_eq!(mode(&dest.path().join("setuid")), 0o754);
+    assert_eq!(mode(&dest.path().join("dir")), 0o755);
+    assert_eq!(mode(&dest.path().join("dir/file")), 0o644);
+}
+
+#[test]
+fn preserve_dir_mode() {
+    let src = setup_src();
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .dir_mode(ModeRule::Preserve)
+        .copy_tree(&src, &dest)
+        .unwrap();
+    assert_eq!(mode(&dest.path().join("dir")), 0o750);
+}
+
+#[test]
+fn read_only_dir_mode_is_set_after_contents_are_copied() {
+    let src = setup_src();
+    let dest_parent = tempfile::tempdir().unwrap();
+    let dest = dest_parent.path().join("dest");
+    CopyOptions::new()
+        .dir_mode(ModeRule::Fixed(0o555))
+        .copy_tree(&src, &dest)
+        .unwrap();
+    assert_eq!(mode(&dest), 0o555);
+    assert_eq!(mode(&dest.join("dir")), 0o555);
+    assert!(dest.join("dir/file").is_file());
+    // Let the tempdir be cleaned up.
+    set_mode(&dest.join("dir"), 0o755);
+    set_mode(&dest, 0o755);
+}
@@ -0,0 +1,59 @@
+// Copyright 2024 Martin Pool
+
+//! Test serializing stats and errors.
+
+#![cfg(feature = "serde")]
+
+use std::fs::write;
+
+use cp_r::*;
+
+#[test]
+fn stats_round_trip_through_json() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"hello").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let stats = CopyOptions::new()
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    let json = serde_json::to_value(&stats).unwrap();
+    assert_eq!(json["files"], 1);
+    assert_eq!(json["file_bytes"], 5);
+    assert_eq!(json["largest_file"], "a");
+
+    let restored: CopyStats = serde_json::from_value(json).unwrap();
+    assert_eq!(restored, stats);
+}
+
+#[test]
+fn error_kinds_round_trip_through_json() {
+    for kind in [
+        ErrorKind::CopyFile,
+        ErrorKind::LimitExceeded(Limit::MaxFiles),
+    ] {
+        let json = serde_json::to_string(&kind).unwrap();
+        assert_eq!(serde_json::from_str::<ErrorKind>(&json).unwrap(), kind);
+    }
+}
+
+#[test]
+fn error_serializes_as_report() {
+    let tmp = tempfile::tempdir().unwrap();
+    let src = tmp.path().join("nonexistent");
+    let dest = tmp.path().join("dest");
+
+    let err = CopyOptions::new().copy_tree(&src, &dest).unwrap_err();
+    let report = err.report();
+    assert_eq!(report.path, src);
+    assert_eq!(report.kind, ErrorKind::ReadDir);
+    assert_eq!(report.io_error_kind.as_deref(), Some("NotFound"));
+    assert_eq!(report.message, err.to_string());
+
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["path"], src.to_str().unwrap());
+    assert_eq!(json["kind"], "ReadDir");
+    assert_eq!(json["io_error_kind"], "NotFound");
+    let restored: ErrorReport = serde_json::from_value(json).unwrap();
+    assert_eq!(restored, report);
+}
@@ -0,0 +1,431 @@
+// Copyright 2024 Martin Pool
+
+//! Test copying trees into tar archives.
+
+#![cfg(feature = "tar")]
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir, write};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use cp_r::*;
+
+/// Read an archive into a map from path to entry type, mode and content.
+fn read_archive(archive: &[u8]) -> BTreeMap<PathBuf, (tar::EntryType, u32, Vec<u8>)> {
+    let mut archive = tar::Archive::new(archive);
+    let mut entries = BTreeMap::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().into_owned();
+        let header = entry.header().clone();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        if header.entry_type() == tar::EntryType::Symlink {
+            content = entry.link_name_bytes().unwrap().into_owned();
+        }
+        entries.insert(path, (header.entry_type(), header.mode().unwrap(), content));
+    }
+    entries
+}
+
+#[test]
+fn copy_tree_to_tar() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"alpha").unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("sub/b"), b"beta!").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("sub/b", src.path().join("link")).unwrap();
+
+    let mut archive = Vec::new();
+    let mut copied = Vec::new();
+    let stats = CopyOptions::new()
+        .after_entry_copied(|path, _, _| {
+            copied.push(path.to_owned());
+            Ok(())
+        })
+        .copy_tree_to_tar(&src, &mut archive)
+        .unwrap();
+
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.dirs, 1);
+    assert_eq!(stats.file_bytes, 10);
+    let entries = read_archive(&archive);
+    assert_eq!(entries.len(), copied.len());
+    let (entry_type, _, content) = &entries[Path::new("sub/b")];
+    assert_eq!(*entry_type, tar::EntryType::Regular);
+    assert_eq!(content, b"beta!");
+    assert_eq!(entries[Path::new("sub")].0, tar::EntryType::Directory);
+    #[cfg(unix)]
+    {
+        assert_eq!(stats.symlinks, 1);
+        let (entry_type, _, target) = &entries[Path::new("link")];
+        assert_eq!(*entry_type, tar::EntryType::Symlink);
+        assert_eq!(target, b"sub/b");
+    }
+}
+
+#[test]
+fn tar_applies_filter_and_modes() {
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    fs.write_file("/src/keep", b"keep").unwrap();
+    fs.write_file("/src/skip.tmp", b"skip").unwrap();
+    fs.set_permissions(Path::new("/src/keep"), 0o4755).unwrap();
+
+    let mut archive = Vec::new();
+    let stats = CopyOptions::new()
+        .file_system(fs)
+        .file_mode(ModeRule::Mask {
+            and: !0o6000,
+            or: 0,
+        })
+        .filter(|path, _| Ok(path.extension().map_or(true, |ext| ext != "tmp")))
+        .copy_tree_to_tar("/src", &mut archive)
+        .unwrap();
+
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.filtered_out, 1);
+    let entries = read_archive(&archive);
+    assert_eq!(entries.keys().collect::<Vec<_>>(), [Path::new("keep")]);
+    assert_eq!(entries[Path::new("keep")].1, 0o755);
+}
+
+#[test]
+fn tar_records_mtime() {
+    let src = tempfile::tempdir().unwrap();
+    let file = src.path().join("file");
+    write(&file, b"").unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(&file, mtime).unwrap();
+
+    let mut archive = Vec::new();
+    CopyOptions::new()
+        .copy_tree_to_tar(&src, &mut archive)
+        .unwrap();
+
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(entry.header().mtime().unwrap(), 1_000_000_000);
+}
+
+/// Build an archive containing one entry, with a path that the tar crate would
+/// refuse to write through its normal API.
+fn archive_with_raw_path(path: &str, entry_type: tar::EntryType) -> Vec<u8> {
+    let mut header = tar::Header::new_old();
+    header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+    header.set_entry_type(entry_type);
+    header.set_mode(0o644);
+    header.set_size(4);
+    header.set_cksum();
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append(&header, b"evil".as_ref()).unwrap();
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn copy_tar_round_trip() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a"), b"alpha").unwrap();
+    create_dir(src.path().join("sub")).unwrap();
+    write(src.path().join("sub/b"), b"beta!").unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(src.path().join("sub/b"), mtime).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(src.path().join("a"), std::fs::Permissions::from_mode(0o751))
+            .unwrap();
+        std::os::unix::fs::symlink("sub/b", src.path().join("link")).unwrap();
+    }
+    let mut archive = Vec::new();
+    CopyOptions::new()
+        .copy_tree_to_tar(&src, &mut archive)
+        .unwrap();
+
+    let dest = tempfile::tempdir().unwrap();
+    let mut seen = Vec::new();
+    let stats = CopyOptions::new()
+        .after_entry_copied(|path, _, _| {
+            seen.push(path.to_owned());
+            Ok(())
+        })
+        .copy_from_tar(archive.as_slice(), dest.path())
+        .unwrap();
+
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.dirs, 1);
+    assert_eq!(stats.file_bytes, 10);
+    assert!(seen.contains(&PathBuf::from("sub/b")));
+    assert_eq!(std::fs::read(dest.path().join("sub/b")).unwrap(), b"beta!");
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(
+            &std::fs::metadata(dest.path().join("sub/b")).unwrap()
+        ),
+        mtime
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dest.path().join("a"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o751);
+        assert_eq!(
+            std::fs::read_link(dest.path().join("link")).unwrap(),
+            Path::new("sub/b")
+        );
+    }
+}
+
+#[test]
+fn copy_from_gzipped_tar_creates_missing_parents() {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, "x/y/z", b"hello".as_ref())
+        .unwrap();
+    let tar = builder.into_inner().unwrap();
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    std::io::Write::write_all(&mut encoder, &tar).unwrap();
+    let gz = encoder.finish().unwrap();
+
+    let dest = tempfile::tempdir().unwrap();
+    let dest_path = dest.path().join("new");
+    let stats = CopyOptions::new()
+        .copy_from_tar(gz.as_slice(), &dest_path)
+        .unwrap();
+
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.dirs, 3, "destination and two parents");
+    assert_eq!(std::fs::read(dest_path.join("x/y/z")).unwrap(), b"hello");
+}
+
+#[test]
+fn copy_from_tar_rejects_unsafe_paths() {
+    for path in ["../evil", "/tmp/evil", "a/../../evil"] {
+        let archive = archive_with_raw_path(path, tar::EntryType::Regular);
+        let parent = tempfile::tempdir().unwrap();
+        let dest = parent.path().join("dest");
+        let err = CopyOptions::new()
+            .copy_from_tar(archive.as_slice(), &dest)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnsafePath, "{}", path);
+        assert_eq!(err.path(), Path::new(path));
+        assert!(!parent.path().join("evil").exists());
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn copy_from_tar_rejects_writing_through_symlinks() {
+    let outside = tempfile::tempdir().unwrap();
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_mode(0o777);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "link", outside.path())
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(4);
+    builder
+        .append_data(&mut header, "link/file", b"evil".as_ref())
+        .unwrap();
+    let archive = builder.into_inner().unwrap();
+
+    let dest = tempfile::tempdir().unwrap();
+    let err = CopyOptions::new()
+        .copy_from_tar(archive.as_slice(), &dest)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnsafePath);
+    assert_eq!(err.path(), Path::new("link/file"));
+    assert!(!outside.path().join("file").exists());
+}
+
+#[test]
+fn copy_from_tar_rejects_file_replacing_symlink() {
+    let outside = tempfile::tempdir().unwrap();
+    let victim = outside.path().join("victim");
+    write(&victim, b"precious").unwrap();
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_mode(0o777);
+    header.set_size(0);
+    builder.append_link(&mut header, "x", &victim).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(4);
+    builder
+        .append_data(&mut header, "x", b"evil".as_ref())
+        .unwrap();
+    let archive = builder.into_inner().unwrap();
+
+    let dest = tempfile::tempdir().unwrap();
+    let err = CopyOptions::new()
+        .copy_from_tar(archive.as_slice(), &dest)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnsafePath);
+    assert_eq!(err.path(), Path::new("x"));
+    assert_eq!(std::fs::read(&victim).unwrap(), b"precious");
+}
+
+#[cfg(unix)]
+#[test]
+fn copy_from_tar_rejects_file_over_existing_symlink() {
+    let outside = tempfile::tempdir().unwrap();
+    let victim = outside.path().join("victim");
+    write(&victim, b"precious").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink(&victim, dest.path().join("x")).unwrap();
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(4);
+    builder
+        .append_data(&mut header, "x", b"evil".as_ref())
+        .unwrap();
+    let archive = builder.into_inner().unwrap();
+
+    let err = CopyOptions::new()
+        .copy_from_tar(archive.as_slice(), &dest)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnsafePath);
+    assert_eq!(std::fs::read(&victim).unwrap(), b"precious");
+}
+
+#[test]
+fn copy_from_tar_filters_directories() {
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    fs.create_dir(Path::new("/src/skip")).unwrap();
+    fs.write_file("/src/skip/file", b"").unwrap();
+    fs.write_file("/src/keep", b"").unwrap();
+    let mut archive = Vec::new();
+    CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_tree_to_tar("/src", &mut archive)
+        .unwrap();
+
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .filter(|path, entry| {
+            assert_eq!(entry.path(), path);
+            Ok(path != Path::new("skip"))
+        })
+        .copy_from_tar(archive.as_slice(), "/dest")
+        .unwrap();
+
+    assert_eq!(stats.filtered_out, 1);
+    assert_eq!(stats.files, 1);
+    assert!(fs.metadata(Path::new("/dest/keep")).is_ok());
+    assert!(fs.metadata(Path::new("/dest/skip")).is_err());
+}
+
+#[test]
+fn copy_from_tar_applies_limits() {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in [("small", "abc"), ("large", "0123456789"), ("a/b/deep", "x")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(content.len() as u64);
+        builder
+            .append_data(&mut header, path, content.as_bytes())
+            .unwrap();
+    }
+    let archive = builder.into_inner().unwrap();
+
+    let fs = MemoryFileSystem::new();
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .max_depth(2)
+        .max_file_size(5)
+        .copy_from_tar(archive.as_slice(), "/dest")
+        .unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.too_large, 1);
+    assert_eq!(stats.too_deep, 1);
+    assert_eq!(fs.read_file("/dest/small").unwrap(), b"abc");
+
+    let err = CopyOptions::new()
+        .file_system(MemoryFileSystem::new())
+        .max_total_bytes(10)
+        .copy_from_tar(archive.as_slice(), "/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::LimitExceeded(Limit::MaxTotalBytes));
+    assert_eq!(err.path(), Path::new("large"));
+}
+
+#[test]
+fn copy_from_tar_maps_paths() {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in [("etc/app.conf.tmpl", "a"), ("etc/other.conf.tmpl", "b")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(content.len() as u64);
+        builder
+            .append_data(&mut header, path, content.as_bytes())
+            .unwrap();
+    }
+    let archive = builder.into_inner().unwrap();
+
+    let fs = MemoryFileSystem::new();
+    CopyOptions::new()
+        .file_system(fs.clone())
+        .map_path(|path, _entry| Ok(Some(Path::new("config").join(path.with_extension("")))))
+        .copy_from_tar(archive.as_slice(), "/dest")
+        .unwrap();
+    assert_eq!(fs.read_file("/dest/config/etc/app.conf").unwrap(), b"a");
+    assert_eq!(fs.read_file("/dest/config/etc/other.conf").unwrap(), b"b");
+
+    let err = CopyOptions::new()
+        .file_system(MemoryFileSystem::new())
+        .map_path(|_path, _entry| Ok(Some(PathBuf::from("same"))))
+        .copy_from_tar(archive.as_slice(), "/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::MappedPathCollision);
+    assert_eq!(err.path(), Path::new("etc/other.conf.tmpl"));
+}
+
+#[test]
+fn tar_transforms_files_both_ways() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("a.txt"), b"alpha").unwrap();
+    write(src.path().join("b"), b"beta").unwrap();
+    let upper = |_path: &Path, reader: &mut dyn Read, writer: &mut dyn std::io::Write| {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        writer.write_all(&content.to_ascii_uppercase())
+    };
+    let is_txt = |path: &Path, _entry: &Entry| path.extension().is_some_and(|e| e == "txt");
+
+    let mut archive = Vec::new();
+    let stats = CopyOptions::new()
+        .transform_files(is_txt, upper)
+        .copy_tree_to_tar(&src, &mut archive)
+        .unwrap();
+    assert_eq!(stats.file_bytes, 9);
+    let entries = read_archive(&archive);
+    assert_eq!(entries[Path::new("a.txt")].2, b"ALPHA");
+    assert_eq!(entries[Path::new("b")].2, b"beta");
+
+    let fs = MemoryFileSystem::new();
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .transform_files(
+            |path, _entry| path == Path::new("b"),
+            |_path, _reader, writer| writer.write_all(b"replaced"),
+        )
+        .copy_from_tar(archive.as_slice(), "/dest")
+        .unwrap();
+    assert_eq!(stats.file_bytes, 13);
+    assert_eq!(fs.read_file("/dest/a.txt").unwrap(), b"ALPHA");
+    assert_eq!(fs.read_file("/dest/b").unwrap(), b"replaced");
+}
@@ -2,7 +2,7 @@
 
 //! Public API tests for `cp_r`.
 
-use std::fs::{create_dir, metadata, read, write, DirEntry, FileType};
+use std::fs::{create_dir, metadata, read, write};
 use std::io;
 use std::path::{Path, PathBuf};
 
@@ -10,6 +10,9 @@ use tempfile::TempDir;
 
 use cp_r::*;
 
+mod util;
+use util::*;
+
 #[test]
 fn basic_copy() {
     let src = tempfile::tempdir().unwrap();
@@ -168,13 +171,9 @@ fn copy_dangling_symlink() {
     println!("{:#?}", stats);
     assert_eq!(
         stats,
-        CopyStats {
-            files: 0,
-            dirs: 0,
-            symlinks: 1,
-            file_bytes: 0,
-            filtered_out: 0,
-        }
+        stats_with(|s| {
+            s.symlinks = 1;
+        })
     );
 }
 
@@ -191,7 +190,7 @@ fn filter_by_path() {
     let file_content = b"some file content\n";
     write(src.path().join("a/aa/aaafile"), file_content).unwrap();
 
-    fn not_b(path: &Path, _: &DirEntry) -> cp_r::Result<bool> {
+    fn not_b(path: &Path, _: &Entry) -> cp_r::Result<bool> {
         Ok(path != Path::new("b"))
     }
     let stats = CopyOptions::new()
@@ -206,13 +205,14 @@ fn filter_by_path() {
     assert!(!dest.path().join("b").exists());
     assert_eq!(
         stats,
-        CopyStats {
-            files: 1,
-            file_bytes: file_content.len() as u64,
-            dirs: 2,
-            symlinks: 0,
-            filtered_out: 1,
-        }
+        stats_with(|s| {
+            s.files = 1;
+            s.file_bytes = file_content.len() as u64;
+            s.dirs = 2;
+            s.filtered_out = 1;
+            s.largest_file = Some(PathBuf::from("a/aa/aaafile"));
+            s.largest_file_bytes = file_content.len() as u64;
+        })
     );
 }
 
@@ -251,13 +251,14 @@ fn filter_by_mut_closure() {
     assert!(!dest.path().join("b").exists());
     assert_eq!(
         stats,
-        CopyStats {
-            files: 1,
-            file_bytes: AAA_CONTENT.len() as u64,
-            dirs: 2,
-            symlinks: 0,
-            filtered_out: 1,
-        }
+        stats_with(|s| {
+            s.files = 1;
+            s.file_bytes = AAA_CONTENT.len() as u64;
+            s.dirs = 2;
+            s.filtered_out = 1;
+            s.largest_file = Some(PathBuf::from("a/aa/aaafile"));
+            s.largest_file_bytes = AAA_CONTENT.len() as u64;
+        })
     );
     // The order in which entries are seen is not guaranteed, and in practice
     // will be partly determined by the unpredictable order that the filesystem
@@ -303,6 +304,37 @@ fn after_entry_copied_callback() {
     );
 }
 
+#[cfg(unix)]
+#[test]
+fn std_read_dir_reads_metadata_when_needed() {
+    let src = make_tree(&[("a", b"hello")]);
+    let entries: Vec<Entry> = StdFileSystem
+        .read_dir(src.path())
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .collect();
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    std::fs::remove_file(entry.path()).unwrap();
+    // The type came from the listing, but the rest of the metadata is read later.
+    assert_eq!(entry.file_type(), FileType::File);
+    assert_eq!(
+        entry.metadata().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn stats_equality_ignores_times() {
+    let a = CopyStats::default();
+    let mut b = CopyStats::default();
+    b.elapsed = std::time::Duration::from_secs(1);
+    b.read_dir_time = std::time::Duration::from_millis(10);
+    assert_eq!(a, b);
+    b.files = 1;
+    assert_ne!(a, b);
+}
+
 #[test]
 fn after_entry_callback_error_terminates_copy() {
     let src = setup_a_b_src();
@@ -334,3 +366,255 @@ fn after_entry_callback_error_terminates_copy() {
         err_debug
     );
 }
+
+#[test]
+fn atime_not_copied_by_default() {
+    let src = tempfile::tempdir().unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let src_file = src.path().join("file");
+    write(&src_file, b"hello").unwrap();
+    let old_atime = filetime::FileTime::from_unix_time(1_000_000_000, 123_456_789);
+    filetime::set_file_atime(&src_file, old_atime).unwrap();
+
+    CopyOptions::new().copy_tree(&src, &dest).unwrap();
+
+    let dest_metadata = metadata(dest.path().join("file")).unwrap();
+    assert_ne!(
+        filetime::FileTime::from_last_access_time(&dest_metadata),
+        old_atime
+    );
+}
+
+#[test]
+fn copy_atime_and_mtime() {
+    let src = tempfile::tempdir().unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let src_file = src.path().join("file");
+    write(&src_file, b"hello").unwrap();
+    let atime = filetime::FileTime::from_unix_time(1_000_000_000, 123_456_789);
+    let mtime = filetime::FileTime::from_unix_time(1_100_000_000, 987_654_321);
+    filetime::set_file_times(&src_file, atime, mtime).unwrap();
+
+    CopyOptions::new()
+        .copy_atime(true)
+        .time_errors(ErrorPolicy::Fail)
+        .copy_tree(&src, &dest)
+        .unwrap();
+
+    let dest_metadata = metadata(dest.path().join("file")).unwrap();
+    // Some filesystems, like FAT, have coarse timestamps.
+    let coarse = |t: filetime::FileTime| t.unix_seconds() / 2;
+    assert_eq!(
+        coarse(filetime::FileTime::from_last_access_time(&dest_metadata)),
+        coarse(atime)
+    );
+    assert_eq!(
+        coarse(filetime::FileTime::from_last_modification_time(
+            &dest_metadata
+        )),
+        coarse(mtime)
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn copy_symlink_times() {
+    use std::fs::symlink_metadata;
+
+    let src = tempfile::tempdir().unwrap();
+    let link = src.path().join("link");
+    std::os::unix::fs::symlink("target", &link).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_100_000_000, 0);
+    filetime::set_symlink_file_times(&link, mtime, mtime).unwrap();
+
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .copy_symlink_times(true)
+        .time_errors(ErrorPolicy::Fail)
+        .copy_tree(&src, &dest)
+        .unwrap();
+    let dest_metadata = symlink_metadata(dest.path().join("link")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&dest_metadata),
+        mtime
+    );
+
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new().copy_tree(&src, &dest).unwrap();
+    let dest_metadata = symlink_metadata(dest.path().join("link")).unwrap();
+    assert_ne!(
+        filetime::FileTime::from_last_modification_time(&dest_metadata),
+        mtime,
+        "symlink times are not copied by default"
+    );
+}
+
+#[test]
+fn copy_with_each_sync_mode() {
+    let mut modes = vec![SyncMode::None, SyncMode::Files, SyncMode::FilesAndDirs];
+    if !cfg!(windows) {
+        modes.push(SyncMode::EndOnly);
+    }
+    for sync_mode in modes {
+        let src = setup_a_b_src();
+        let dest_parent = tempfile::tempdir().unwrap();
+        let dest = dest_parent.path().join("dest");
+        let stats = CopyOptions::new()
+            .sync(sync_mode)
+            .copy_tree(&src, &dest)
+            .unwrap();
+        assert_eq!(stats.files, 1, "{:?}", sync_mode);
+        assert_eq!(stats.dirs, 5, "{:?}", sync_mode);
+        assert_eq!(read(dest.join("a/aa/aaafile")).unwrap(), AAA_CONTENT);
+    }
+}
+
+#[test]
+fn iterate_copy_events() {
+    let src = setup_a_b_src();
+    let dest = tempfile::tempdir().unwrap();
+    let mut iter = CopyOptions::new()
+        .filter(|path, _| Ok(path != Path::new("b/bb")))
+        .into_iter(&src, &dest);
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    let mut files: Vec<(PathBuf, u64)> = Vec::new();
+    let mut filtered: Vec<PathBuf> = Vec::new();
+    for event in &mut iter {
+        match event {
+            CopyEvent::DirCreated { path } => dirs.push(path),
+            CopyEvent::FileCopied { path, bytes } => files.push((path, bytes)),
+            CopyEvent::FilteredOut { path } => filtered.push(path),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+    dirs.sort();
+    assert_eq!(
+        dirs,
+        [Path::new("a"), Path::new("a/aa"), Path::new("b")].map(Path::to_owned)
+    );
+    assert_eq!(
+        files,
+        [(PathBuf::from("a/aa/aaafile"), AAA_CONTENT.len() as u64)]
+    );
+    assert_eq!(filtered, [PathBuf::from("b/bb")]);
+    assert_eq!(
+        *iter.stats(),
+        stats_with(|s| {
+            s.files = 1;
+            s.file_bytes = AAA_CONTENT.len() as u64;
+            s.dirs = 3;
+            s.filtered_out = 1;
+            s.largest_file = Some(PathBuf::from("a/aa/aaafile"));
+            s.largest_file_bytes = AAA_CONTENT.len() as u64;
+        })
+    );
+    assert!(iter.next().is_none(), "iterator is fused");
+}
+
+#[test]
+fn iterate_creating_destination() {
+    let src = setup_a_b_src();
+    let dest_parent = tempfile::tempdir().unwrap();
+    let dest = dest_parent.path().join("dest");
+    let first = CopyOptions::new().into_iter(&src, &dest).next().unwrap();
+    match first {
+        CopyEvent::DirCreated { path } => assert_eq!(path, Path::new("")),
+        other => panic!("unexpected event {:?}", other),
+    }
+    assert!(dest.is_dir());
+}
+
+#[test]
+fn stop_iterating_early() {
+    let src = setup_a_b_src();
+    let dest = tempfile::tempdir().unwrap();
+    let events: Vec<CopyEvent> = CopyOptions::new().into_iter(&src, &dest).take(1).collect();
+    assert_eq!(events.len(), 1);
+    // Only one of the top-level directories was created.
+    assert!(dest.path().join("a").is_dir() != dest.path().join("b").is_dir());
+}
+
+#[test]
+fn iteration_ends_with_error() {
+    let dest = tempfile::tempdir().unwrap();
+    let events: Vec<CopyEvent> = CopyOptions::new().into_iter("nothing", &dest).collect();
+    assert_eq!(events.len(), 1);
+    match &events[0] {
+        CopyEvent::Error(err) => assert_eq!(err.kind(), ErrorKind::ReadDir),
+        other => panic!("unexpected event {:?}", other),
+    }
+}
+
+#[test]
+fn copy_into_own_subdirectory_fails_upfront() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("file"), b"content").unwrap();
+    let dest = src.path().join("sub").join("dest");
+    create_dir(src.path().join("sub")).unwrap();
+
+    let err = CopyOptions::new().copy_tree(src.path(), &dest).unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::DestinationInsideSource);
+    assert_eq!(err.path(), dest);
+    assert!(!dest.exists());
+
+    let err = CopyOptions::new()
+        .copy_tree(src.path(), src.path())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::DestinationInsideSource);
+}
+
+#[test]
+fn copy_to_sibling_through_parent_dir() {
+    let tmp = tempfile::tempdir().unwrap();
+    let src = tmp.path().join("src");
+    create_dir(&src).unwrap();
+    write(src.join("file"), b"content").unwrap();
+
+    // `src/..` is not inside `src`, even though its path starts with it.
+    let dest = src.join("../backup");
+    let stats = CopyOptions::new().copy_tree(&src, &dest).unwrap();
+
+    assert_eq!(stats.files, 1);
+    assert_eq!(read(tmp.path().join("backup/file")).unwrap(), b"content");
+}
+
+#[cfg(unix)]
+#[test]
+fn copy_into_source_through_symlink_fails() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("file"), b"content").unwrap();
+    let other = tempfile::tempdir().unwrap();
+    let link = other.path().join("link");
+    std::os::unix::fs::symlink(src.path(), &link).unwrap();
+
+    let err = CopyOptions::new()
+        .copy_tree(src.path(), link.join("dest"))
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::DestinationInsideSource);
+    assert!(!src.path().join("dest").exists());
+}
+
+#[test]
+fn stats_describe_file_sizes_and_timing() {
+    let src = tempfile::tempdir().unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    write(src.path().join("empty"), b"").unwrap();
+    write(src.path().join("small"), b"abc").unwrap();
+    write(src.path().join("large"), b"0123456789").unwrap();
+
+    let stats = CopyOptions::new()
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.empty_files, 1);
+    assert_eq!(stats.largest_file.as_deref(), Some(Path::new("large")));
+    assert_eq!(stats.largest_file_bytes, 10);
+    assert!(stats.ignored_errors.is_empty());
+    assert!(stats.elapsed > std::time::Duration::ZERO);
+    assert!(stats.elapsed >= stats.read_dir_time + stats.copy_file_time);
+    assert!(stats.bytes_per_second() > 0.0);
+    assert_eq!(CopyStats::default().bytes_per_second(), 0.0);
+}
@@ -0,0 +1,128 @@
+// Copyright 2024 Martin Pool
+
+//! Test transforming the content of files while they're copied.
+
+use std::fs::{metadata, read, write};
+use std::io;
+use std::path::Path;
+
+use filetime::FileTime;
+
+use cp_r::*;
+
+#[test]
+fn transform_matching_files() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("app.conf"), b"name = {{NAME}}\n").unwrap();
+    write(src.path().join("data.bin"), b"{{NAME}}").unwrap();
+    let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(src.path().join("app.conf"), mtime).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(
+            src.path().join("app.conf"),
+            std::fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+    }
+    let dest = tempfile::tempdir().unwrap();
+
+    let mut transformed = Vec::new();
+    let stats = CopyOptions::new()
+        .transform_files(
+            |path, _entry| path.extension().is_some_and(|ext| ext == "conf"),
+            |path, reader, writer| {
+                transformed.push(path.to_owned());
+                let mut template = String::new();
+                reader.read_to_string(&mut template)?;
+                writer.write_all(template.replace("{{NAME}}", "demo").as_bytes())
+            },
+        )
+        .copy_tree(src.path(), dest.path())
+        .unwrap();
+
+    assert_eq!(transformed, [Path::new("app.conf")]);
+    assert_eq!(
+        read(dest.path().join("app.conf")).unwrap(),
+        b"name = demo\n"
+    );
+    assert_eq!(read(dest.path().join("data.bin")).unwrap(), b"{{NAME}}");
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.file_bytes, 12 + 8);
+    let dest_metadata = metadata(dest.path().join("app.conf")).unwrap();
+    assert_eq!(FileTime::from_last_modification_time(&dest_metadata), mtime);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(dest_metadata.permissions().mode() & 0o7777, 0o640);
+    }
+}
+
+#[test]
+fn transform_error_stops_the_copy() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("bad.conf"), b"{{").unwrap();
+    let dest = tempfile::tempdir().unwrap();
+
+    let err = CopyOptions::new()
+        .transform_files(
+            |_path, _entry| true,
+            |_path, _reader, _writer| {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "unclosed tag"))
+            },
+        )
+        .copy_tree(src.path(), dest.path())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::TransformFile);
+    assert_eq!(err.path(), src.path().join("bad.conf"));
+    assert_eq!(err.io_error().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn transform_in_memory_with_fixed_mode() {
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    fs.write_file("/src/a", b"lower").unwrap();
+
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .file_mode(ModeRule::Fixed(0o600))
+        .transform_files(
+            |_path, _entry| true,
+            |_path, reader, writer| {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                writer.write_all(&content.to_ascii_uppercase())
+            },
+        )
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    assert_eq!(fs.read_file("/dest/a").unwrap(), b"LOWER");
+    assert_eq!(fs.metadata(Path::new("/dest/a")).unwrap().mode, 0o600);
+    assert_eq!(stats.file_bytes, 5);
+}
+
+#[test]
+fn transform_tolerates_unsupported_permissions() {
+    // As on Windows, where permissions can't be set.
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    fs.write_file("/src/a", b"hello").unwrap();
+    let faulty = FaultyFileSystem::new(fs.clone())
+        .fail(Fault::new(Operation::SetPermissions).error_kind(io::ErrorKind::Unsupported));
+
+    let stats = CopyOptions::new()
+        .file_system(faulty)
+        .transform_files(
+            |_path, _entry| true,
+            |_path, reader, writer| io::copy(reader, writer).map(|_| ()),
+        )
+        .copy_tree("/src", "/dest")
+        .unwrap();
+
+    assert_eq!(fs.read_file("/dest/a").unwrap(), b"hello");
+    assert_eq!(stats.ignored_errors[&ErrorKind::SetPermissions], 1);
+}
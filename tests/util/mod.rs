@@ -0,0 +1,64 @@
+// Copyright 2024 Martin Pool
+
+//! Fixtures shared by the integration tests.
+
+// Each test binary uses only some of these.
+#![allow(dead_code)]
+
+use std::fs::{create_dir_all, write};
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use cp_r::{CopyStats, FileSystem, MemoryFileSystem};
+
+/// Make a temporary source tree from a list of relative paths and file contents.
+///
+/// Parent directories are created as needed, and a path ending in `/` is created as an
+/// empty directory.
+pub fn make_tree(entries: &[(&str, &[u8])]) -> TempDir {
+    let src = tempfile::tempdir().unwrap();
+    for (path, content) in entries {
+        let path = src.path().join(path);
+        if path.as_os_str().to_string_lossy().ends_with('/') {
+            create_dir_all(&path).unwrap();
+        } else {
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, content).unwrap();
+        }
+    }
+    src
+}
+
+/// Make a source tree in a [MemoryFileSystem] at `/src`, from a list of relative paths and
+/// file contents, as for [make_tree].
+pub fn make_memory_tree(entries: &[(&str, &[u8])]) -> MemoryFileSystem {
+    let fs = MemoryFileSystem::new();
+    fs.create_dir(Path::new("/src")).unwrap();
+    for (path, content) in entries {
+        let path = Path::new("/src").join(path);
+        let is_dir = path.as_os_str().to_string_lossy().ends_with('/');
+        let parent = if is_dir {
+            path.as_path()
+        } else {
+            path.parent().unwrap()
+        };
+        for dir in parent.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            if fs.symlink_metadata(dir).is_err() {
+                fs.create_dir(dir).unwrap();
+            }
+        }
+        if !is_dir {
+            fs.write_file(&path, content).unwrap();
+        }
+    }
+    fs
+}
+
+/// Make the expected [CopyStats] by setting some counters, leaving the rest at their
+/// defaults, since [CopyStats] can't be built with a struct literal outside the crate.
+pub fn stats_with(set: impl FnOnce(&mut CopyStats)) -> CopyStats {
+    let mut stats = CopyStats::default();
+    set(&mut stats);
+    stats
+}
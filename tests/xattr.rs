@@ -0,0 +1,193 @@
+// Copyright 2024 Martin Pool
+
+//! Test copying extended attributes and POSIX ACLs.
+
+#![cfg(target_os = "linux")]
+
+use std::ffi::CString;
+use std::fs::{create_dir, write};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use cp_r::*;
+
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let c_name = CString::new(name).unwrap();
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let c_name = CString::new(name).unwrap();
+    let mut buf = vec![0u8; 1024];
+    let len = unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        None
+    } else {
+        buf.truncate(len as usize);
+        Some(buf)
+    }
+}
+
+/// Make a source tree with xattrs on a file and a directory, or return None if the
+/// filesystem doesn't support user xattrs.
+fn setup_src_with_xattrs() -> Option<tempfile::TempDir> {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("file"), b"content").unwrap();
+    create_dir(src.path().join("dir")).unwrap();
+    if let Err(err) = set_xattr(&src.path().join("file"), "user.checksum", b"1234") {
+        println!("skipping test because the filesystem doesn't support xattrs: {err}");
+        return None;
+    }
+    set_xattr(&src.path().join("dir"), "user.provenance", b"build 7").unwrap();
+    Some(src)
+}
+
+#[test]
+fn xattrs_not_copied_by_default() {
+    let src = match setup_src_with_xattrs() {
+        Some(src) => src,
+        None => return,
+    };
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new().copy_tree(&src, &dest).unwrap();
+    assert_eq!(get_xattr(&dest.path().join("file"), "user.checksum"), None);
+}
+
+#[test]
+fn copy_xattrs_on_files_and_dirs() {
+    let src = match setup_src_with_xattrs() {
+        Some(src) => src,
+        None => return,
+    };
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .copy_xattrs(true)
+        .copy_tree(&src, &dest)
+        .unwrap();
+    assert_eq!(
+        get_xattr(&dest.path().join("file"), "user.checksum").as_deref(),
+        Some(b"1234".as_ref())
+    );
+    assert_eq!(
+        get_xattr(&dest.path().join("dir"), "user.provenance").as_deref(),
+        Some(b"build 7".as_ref())
+    );
+}
+
+#[test]
+fn xattrs_outside_selected_namespaces_are_not_copied() {
+    let src = match setup_src_with_xattrs() {
+        Some(src) => src,
+        None => return,
+    };
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .copy_xattrs(true)
+        .xattr_namespaces(["trusted"])
+        .copy_tree(&src, &dest)
+        .unwrap();
+    assert_eq!(get_xattr(&dest.path().join("file"), "user.checksum"), None);
+}
+
+/// Build the Linux xattr encoding of an ACL granting `rw` to the owner, and `r` to
+/// uid 1234, the group and others.
+fn example_acl() -> Vec<u8> {
+    const ACL_USER_OBJ: u16 = 0x01;
+    const ACL_USER: u16 = 0x02;
+    const ACL_GROUP_OBJ: u16 = 0x04;
+    const ACL_MASK: u16 = 0x10;
+    const ACL_OTHER: u16 = 0x20;
+    const UNDEFINED_ID: u32 = u32::MAX;
+    let entries = [
+        (ACL_USER_OBJ, 6, UNDEFINED_ID),
+        (ACL_USER, 4, 1234),
+        (ACL_GROUP_OBJ, 4, UNDEFINED_ID),
+        (ACL_MASK, 4, UNDEFINED_ID),
+        (ACL_OTHER, 4, UNDEFINED_ID),
+    ];
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        acl.extend_from_slice(&u16::to_le_bytes(tag));
+        acl.extend_from_slice(&u16::to_le_bytes(perm));
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+    acl
+}
+
+#[test]
+fn copy_access_and_default_acls() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("file"), b"content").unwrap();
+    create_dir(src.path().join("dir")).unwrap();
+    let acl = example_acl();
+    if let Err(err) = set_xattr(&src.path().join("file"), "system.posix_acl_access", &acl) {
+        println!("skipping test because the filesystem doesn't support ACLs: {err}");
+        return;
+    }
+    set_xattr(&src.path().join("dir"), "system.posix_acl_default", &acl).unwrap();
+
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new()
+        .copy_acls(true)
+        .copy_tree(&src, &dest)
+        .unwrap();
+
+    assert_eq!(
+        get_xattr(&dest.path().join("file"), "system.posix_acl_access"),
+        Some(acl.clone())
+    );
+    assert_eq!(
+        get_xattr(&dest.path().join("dir"), "system.posix_acl_default"),
+        Some(acl.clone())
+    );
+    // New files created in the copied directory inherit the default ACL.
+    write(dest.path().join("dir/new"), b"").unwrap();
+    assert_eq!(
+        get_xattr(&dest.path().join("dir/new"), "system.posix_acl_access"),
+        Some(acl)
+    );
+}
+
+#[test]
+fn acls_not_copied_by_default() {
+    let src = tempfile::tempdir().unwrap();
+    write(src.path().join("file"), b"content").unwrap();
+    if set_xattr(
+        &src.path().join("file"),
+        "system.posix_acl_access",
+        &example_acl(),
+    )
+    .is_err()
+    {
+        return;
+    }
+    let dest = tempfile::tempdir().unwrap();
+    CopyOptions::new().copy_tree(&src, &dest).unwrap();
+    assert_eq!(
+        get_xattr(&dest.path().join("file"), "system.posix_acl_access"),
+        None
+    );
+}
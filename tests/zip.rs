@@ -0,0 +1,80 @@
+// Copyright 2024 Martin Pool
+
+//! Test copying trees out of zip archives.
+
+#![cfg(feature = "zip")]
+
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use zip::write::SimpleFileOptions;
+
+use cp_r::*;
+
+fn make_zip(entries: &[(&str, Option<&[u8]>)]) -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, content) in entries {
+        let options = SimpleFileOptions::default().unix_permissions(0o640);
+        match content {
+            Some(content) => {
+                writer.start_file(*name, options).unwrap();
+                writer.write_all(content).unwrap();
+            }
+            None => writer.add_directory(*name, options).unwrap(),
+        }
+    }
+    writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn copy_from_zip() {
+    let archive = make_zip(&[
+        ("dir/", None),
+        ("dir/file", Some(b"hello")),
+        ("other/file", Some(b"world")),
+    ]);
+    let fs = MemoryFileSystem::new();
+    let stats = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_from_zip(Cursor::new(archive), "/dest")
+        .unwrap();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.dirs, 3);
+    assert_eq!(stats.file_bytes, 10);
+    assert_eq!(fs.read_file("/dest/dir/file").unwrap(), b"hello");
+    assert_eq!(fs.read_file("/dest/other/file").unwrap(), b"world");
+    assert_eq!(
+        fs.metadata(Path::new("/dest/dir/file")).unwrap().mode,
+        0o640
+    );
+}
+
+#[test]
+fn copy_from_zip_rejects_parent_paths() {
+    let archive = make_zip(&[("../evil", Some(b"evil"))]);
+    let fs = MemoryFileSystem::new();
+    let err = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_from_zip(Cursor::new(archive), "/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnsafePath);
+    assert!(fs.metadata(Path::new("/evil")).is_err());
+}
+
+#[test]
+fn copy_from_zip_rejects_file_over_existing_symlink() {
+    let archive = make_zip(&[("x", Some(b"evil"))]);
+    let fs = MemoryFileSystem::new();
+    fs.write_file("/victim", b"precious").unwrap();
+    fs.create_dir(Path::new("/dest")).unwrap();
+    fs.symlink(Path::new("/victim"), Path::new("/dest/x"), false)
+        .unwrap();
+
+    let err = CopyOptions::new()
+        .file_system(fs.clone())
+        .copy_from_zip(Cursor::new(archive), "/dest")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnsafePath);
+    assert_eq!(err.path(), Path::new("x"));
+    assert_eq!(fs.read_file("/victim").unwrap(), b"precious");
+}